@@ -14,20 +14,27 @@ global tss
 global tss_entry
 global int_stack
 global p4_table
+global p5_table
 
 global check_cpuid
 global check_long_mode
 global enable_paging
 global set_up_SSE
 global gdt_pointer
+global boot_gdt_pointer
 global gdt_data
 global gdt_code
+global stack_top
 
+; the kernel is linked here, physical 0 is mapped at this address
+KERNEL_BASE equ 0xFFFFFFFF80000000
 
-section .text
+; runs before paging is on, so this is linked at its physical address.
+; Everything outside of it has to be reached as `symbol - KERNEL_BASE`
+section .boot progbits alloc exec nowrite
 bits 32
 start:
-    mov esp, stack_top
+    mov esp, stack_top - KERNEL_BASE
     ; Move Multiboot info pointer to edi to pass it to the kernel. We must not
     ; modify the `edi` register until the kernel it called.
     mov edi, ebx
@@ -46,8 +53,8 @@ start:
     call set_up_pic
 
 
-    ; load the 64-bit GDT
-    lgdt [gdt_pointer]
+    ; load the 64-bit GDT, through its physical address for now
+    lgdt [boot_gdt_pointer]
     ;hlt
     ; update selectors
     mov ax, gdt_data
@@ -79,20 +86,31 @@ set_up_pic:
     out 0x21, al
     out 0xA1, al     ;done!
 set_up_page_tables:
-    ; recursive map P4
-    mov eax, p4_table
+    ; the first GiB is mapped three times: identity mapped for this code
+    ; and the AP trampoline, at the start of the direct map in the first
+    ; upper half slot, and at -2 GiB for the kernel image
+    mov eax, p3_table - KERNEL_BASE
     or eax, 0b11 ; present + writable
-    mov [p4_table + 511 * 8], eax
+    mov [p4_table - KERNEL_BASE], eax
 
-    ; map first P4 entry to P3 table
-    mov eax, p3_table
+    mov eax, p3_phys_table - KERNEL_BASE
     or eax, 0b11 ; present + writable
-    mov [p4_table], eax
+    mov [p4_table - KERNEL_BASE + 256 * 8], eax
 
-    ; map first P3 entry to P2 table
-    mov eax, p2_table
+    mov eax, p3_kernel_table - KERNEL_BASE
     or eax, 0b11 ; present + writable
-    mov [p3_table], eax
+    mov [p4_table - KERNEL_BASE + 511 * 8], eax
+
+    ; the identity map and the direct map share their P2 table
+    mov eax, p2_table - KERNEL_BASE
+    or eax, 0b11 ; present + writable
+    mov [p3_table - KERNEL_BASE], eax
+    mov [p3_phys_table - KERNEL_BASE], eax
+
+    ; the kernel image gets its own, its permissions are changed later
+    mov eax, p2_kernel_table - KERNEL_BASE
+    or eax, 0b11 ; present + writable
+    mov [p3_kernel_table - KERNEL_BASE + 510 * 8], eax
 
     ; map each P2 entry to a huge 2MiB page
     mov ecx, 0 ; counter variable
@@ -101,17 +119,25 @@ set_up_page_tables:
     mov eax, 0x200000  ; 2MiB
     mul ecx            ; start address of ecx-th page
     or eax, 0b10000011 ; present + writable + huge
-    mov [p2_table + ecx * 8], eax ; map ecx-th entry
+    mov [p2_table - KERNEL_BASE + ecx * 8], eax ; map ecx-th entry
+    mov [p2_kernel_table - KERNEL_BASE + ecx * 8], eax
 
     inc ecx            ; increase counter
     cmp ecx, 512       ; if counter == 512, the whole P2 table is mapped
     jne .map_p2_table  ; else map the next entry
 
+    ; with five levels the P4 hangs off the first and the last P5 slot,
+    ; which gives the same addresses as above
+    mov eax, p4_table - KERNEL_BASE
+    or eax, 0b11 ; present + writable
+    mov [p5_table - KERNEL_BASE], eax
+    mov [p5_table - KERNEL_BASE + 511 * 8], eax
+
     ret
 
 enable_paging:
     ; load P4 to cr3 register (cpu uses this to access the P4 table)
-    mov eax, p4_table
+    mov eax, p4_table - KERNEL_BASE
     mov cr3, eax
 
     ; enable PAE-flag in cr4 (Physical Address Extension)
@@ -119,15 +145,57 @@ enable_paging:
     or eax, 1 << 5
     mov cr4, eax
 
+    ; five-level paging if the cpu has it (CPUID.7.0:ECX.LA57), it
+    ; cannot be switched once long mode is active
+    mov eax, 0x0
+    cpuid
+    cmp eax, 0x7
+    jb .no_la57
+    mov eax, 0x7
+    xor ecx, ecx
+    cpuid
+    test ecx, 1 << 16
+    jz .no_la57
+    mov eax, p5_table - KERNEL_BASE
+    mov cr3, eax
+    mov eax, cr4
+    or eax, 1 << 12
+    mov cr4, eax
+.no_la57:
+
     ; set the long mode bit in the EFER MSR (model specific register)
     mov ecx, 0xC0000080
     rdmsr
     or eax, 1 << 8
     wrmsr
 
-    ; enable paging in the cr0 register
+    ; and the no-execute enable bit, if the cpu has it
+    mov eax, 0x80000001
+    cpuid
+    test edx, 1 << 20
+    jz .no_nx
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, 1 << 11
+    wrmsr
+.no_nx:
+
+    ; the PAT resets to WB, WT, UC-, UC, WB, WT, UC-, UC. Entry 2
+    ; (PCD without PWT) becomes write-combining, the others stay
+    mov eax, 0x1
+    cpuid
+    test edx, 1 << 16
+    jz .no_pat
+    mov ecx, 0x277
+    mov eax, 0x00010406
+    mov edx, 0x00070406
+    wrmsr
+.no_pat:
+
+    ; enable paging in the cr0 register, with write protection
+    ; honoured in ring 0 as well (copy on write relies on it)
     mov eax, cr0
-    or eax, 1 << 31
+    or eax, 1 << 31 | 1 << 16
     mov cr0, eax
 
     ret
@@ -234,14 +302,27 @@ set_up_SSE:
 
 
 
+; 32-bit lgdt only takes a 32-bit base
+boot_gdt_pointer:
+    dw gdt_pointer - gdt64 - 1
+    dd gdt64 - KERNEL_BASE
+
 section .bss
 align 4096
+p5_table:
+    resb 4096
 p4_table:
     resb 4096
 p3_table:
     resb 4096
+p3_phys_table:
+    resb 4096
+p3_kernel_table:
+    resb 4096
 p2_table:
     resb 4096
+p2_kernel_table:
+    resb 4096
 stack_bottom:
     resb 4096 * 8
 stack_top:
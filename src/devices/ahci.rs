@@ -1,14 +1,15 @@
 use super::pci;
 use super::mmio::MMIO;
 use super::pci::PCI_DEVICES;
-use mem::paging;
+use mem::paging::{self, phys_to_virt, CacheMode, MmioRegion};
+use mem::dma::{DmaBuffer, Zone};
 use collections::vec::Vec;
 use collections::String;
 use fs::block::BlockDevice;
 use alloc::boxed::Box;
 use core::default::Default;
-use core::ptr::Unique;
-use core::mem::{transmute, transmute_copy};
+use core::ptr;
+use core::mem::{size_of, transmute_copy};
 use mem::FRAME;
 use core::str;
 use core::slice;
@@ -51,6 +52,7 @@ pub fn global_HBA_status() {
 
 pub struct HBAController {
     device: pci::PCIDevice,
+    regs: MmioRegion,
     reg_base: usize,
     CAP: MMIO<u32>,
     GHC: MMIO<u32>,
@@ -70,8 +72,11 @@ pub struct HBAPort {
     PxCI: MMIO<u32>,
     PxSSTS: MMIO<u32>,
     PxTFD: MMIO<u32>,
-    command_list: Unique<[CommandHeader; 32]>,
-    received_fis: Unique<[u8; 256]>,
+    command_list: DmaBuffer,
+    received_fis: DmaBuffer,
+    // per slot, the command table and the sector it transfers
+    tables: Vec<DmaBuffer>,
+    bufs: Vec<DmaBuffer>,
     slot_free_map: [AtomicBool; 32]
 }
 
@@ -80,12 +85,13 @@ struct CommandHeader {
     flags: u16,
     PRDTL: u16,
     PRDBC: u32,
-    CTBA: Unique<CommandTable>,
+    // physical address of the command table
+    CTBA: u64,
     pad: [u32; 4]
 }
 
-impl Default for CommandHeader {
-    fn default() -> Self {
+impl CommandHeader {
+    fn new(table: &DmaBuffer) -> Self {
         CommandHeader {
             flags: 5,
             // command header length = 5
@@ -93,12 +99,7 @@ impl Default for CommandHeader {
             // one physical region descriptor
             PRDBC: 511,
             // transfers 512 bytes (one block) by default
-            //CTBA: unsafe {Unique::new(Box::into_raw(box CommandTable::default()))},
-            CTBA: unsafe {
-                let p = FRAME.alloc() as *mut CommandTable;
-                *p = CommandTable::default();
-                Unique::new(p)
-            },
+            CTBA: table.bus_addr() as u64,
             pad: [0; 4]
         }
     }
@@ -131,17 +132,18 @@ struct DMACommand {
 struct CommandTable {
     CFIS: DMACommand,
     pad: [u8; 108],
+    // physical, like every address the HBA sees
     database_address: u64,
     pad2: u32,
     byte_count: u32
 }
 
-impl Default for CommandTable {
-    fn default() -> Self {
+impl CommandTable {
+    fn new(buf: &DmaBuffer) -> Self {
         CommandTable {
             CFIS: DMACommand::default(),
             pad: [0; 108],
-            database_address: FRAME.alloc() as u64,
+            database_address: buf.bus_addr() as u64,
             pad2: 0,
             byte_count: 511 | 1 << 31
         }
@@ -150,19 +152,24 @@ impl Default for CommandTable {
 
 
 const AHCI_BA_OFFSET: u16 = 0x24;
+// generic host control followed by the registers of 32 ports
+const AHCI_REGS_LEN: usize = 0x100 + 32 * 0x80;
+// CAP.S64A, the HBA takes 64 bit addresses
+const CAP_S64A: u32 = 1 << 31;
 
 fn init_ahci_controller(dev: pci::PCIDevice) -> HBAController {
-    let base_address: usize = pci::pci_read32(dev.bus, dev.device, 0, AHCI_BA_OFFSET) as usize;
-    kprint!("ahci base address 0x{:x}\n", base_address);
-    paging::map_volatile(base_address);
+    let abar: usize = pci::pci_read32(dev.bus, dev.device, 0, AHCI_BA_OFFSET) as usize;
+    kprint!("ahci base address 0x{:x}\n", abar);
+    let regs = paging::map_mmio(abar, AHCI_REGS_LEN, CacheMode::Uncached);
 
     let ret = HBAController {
         device: dev,
-        reg_base: base_address,
-        CAP: MMIO::new(base_address as *mut u32),
-        GHC: MMIO::new((base_address + 0x4) as *mut u32),
-        IS: MMIO::new((base_address + 0x8) as *mut u32),
-        PI: MMIO::new((base_address + 0xC) as *mut u32),
+        reg_base: regs.base(),
+        CAP: regs.reg(0x0),
+        GHC: regs.reg(0x4),
+        IS: regs.reg(0x8),
+        PI: regs.reg(0xC),
+        regs: regs,
     };
 
     kprint!("ahci cap: 0x{:x}\n", ret.CAP.get());
@@ -188,6 +195,17 @@ impl HBAController {
         }
         vec
     }
+
+    ///
+    /// where memory the HBA reads and writes has to come from
+    ///
+    fn dma_zone(&self) -> Zone {
+        if self.CAP.get() & CAP_S64A != 0 { Zone::Normal } else { Zone::Dma32 }
+    }
+}
+
+fn dma_alloc(len: usize, zone: Zone) -> DmaBuffer {
+    DmaBuffer::new(len, zone).expect("ahci: no memory for DMA")
 }
 
 impl HBAPort {
@@ -198,9 +216,20 @@ impl HBAPort {
             return None;
         }
 
-        let headers: &mut [CommandHeader; 32] = unsafe { (FRAME.alloc() as *mut _).as_mut().unwrap() };
+        let zone = controller.dma_zone();
+        let command_list = dma_alloc(32 * size_of::<CommandHeader>(), zone);
+        let mut tables = Vec::new();
+        let mut bufs = Vec::new();
         for x in 0..32 {
-            headers[x] = CommandHeader::default();
+            let table = dma_alloc(size_of::<CommandTable>(), zone);
+            let buf = dma_alloc(512, zone);
+            unsafe {
+                ptr::write(table.as_ptr() as *mut CommandTable, CommandTable::new(&buf));
+                ptr::write((command_list.as_ptr() as *mut CommandHeader).offset(x as isize),
+                           CommandHeader::new(&table));
+            }
+            tables.push(table);
+            bufs.push(buf);
         }
 
         let ret = HBAPort {
@@ -215,8 +244,10 @@ impl HBAPort {
             PxSACT: MMIO::new((base_address + 0x34) as *mut u32),
             PxCI: MMIO::new((base_address + 0x38) as *mut u32),
             PxSSTS: MMIO::new((base_address + 0x28) as *mut u32),
-            command_list: unsafe { Unique::new(headers) },
-            received_fis: unsafe { Unique::new(FRAME.alloc() as *mut _) },
+            command_list: command_list,
+            received_fis: dma_alloc(256, zone),
+            tables: tables,
+            bufs: bufs,
             slot_free_map: init_array!(AtomicBool, 32, AtomicBool::new(false))
         };
 
@@ -225,29 +256,28 @@ impl HBAPort {
         ret.PxCMD.set(ret.PxCMD.get() & !1);
         ret.PxCMD.set(ret.PxCMD.get() & !(1 << 4)); // stop device
 
-        unsafe {
-            ret.PxCLB.set(ret.command_list.get().as_ptr() as u64);
-            ret.PxFB.set(ret.received_fis.get() as *const _ as u64);
-        }
+        ret.PxCLB.set(ret.command_list.bus_addr() as u64);
+        ret.PxFB.set(ret.received_fis.bus_addr() as u64);
         ret.PxCMD.set(ret.PxCMD.get() | 1 << 4); // start device
         ret.PxCMD.set(ret.PxCMD.get() | 1);
         Some(ret)
     }
 
     fn get_header(&self, i: usize) -> &mut CommandHeader {
+        assert!(i < 32);
         unsafe {
-            transmute::<_, &mut _>(&self.command_list.get()[i])
+            &mut *(self.command_list.as_ptr() as *mut CommandHeader).offset(i as isize)
         }
     }
 
     fn get_table(&self, i: usize) -> &mut CommandTable {
         unsafe {
-            transmute::<_, &mut _>(self.get_header(i).CTBA.get())
+            &mut *(self.tables[i].as_ptr() as *mut CommandTable)
         }
     }
 
     fn get_buf(&self, i: usize) -> *mut u8 {
-        self.get_table(i).database_address as *mut u8
+        self.bufs[i].as_ptr()
     }
 
     fn get_free_slot(&self) -> usize {
@@ -384,7 +414,7 @@ impl BlockDevice for HBAPort {
         self.PxIS.set(!0);
 
         unsafe {
-            let test_1: &mut usize = (FRAME.alloc() as *mut usize).as_mut().unwrap();
+            let test_1: &mut usize = (phys_to_virt(FRAME.alloc()) as *mut usize).as_mut().unwrap();
             *test_1 = 0x2333333;
             self.write_block_raw(transmute_copy(&test_1), 20);
 
@@ -5,6 +5,11 @@ use bit_field::BitField;
 use core::sync::atomic::*;
 
 pub static CPU_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+// bit n is set once cpu n has its local apic up
+static ONLINE_CPUS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// upper bound on x2APIC ids for statically sized per-cpu tables
+pub const MAX_CPU: usize = 64;
 
 pub fn mp_apic_init() -> u32 {
     CPU_COUNT.fetch_add(1, Ordering::Relaxed);
@@ -24,10 +29,24 @@ pub fn mp_apic_init() -> u32 {
         io::outb(0x21, 0xff);
         msr::wrmsr(msr::IA32_X2APIC_EOI, 0);
         msr::wrmsr(msr::IA32_X2APIC_SIVR, 1 << 8 | 20); // setup spurious interrupt handler. Important!
-        get_cpu_id()
+        let id = get_cpu_id();
+        assert!((id as usize) < MAX_CPU, "cpu id {} too large", id);
+        ONLINE_CPUS.fetch_or(1 << id, Ordering::SeqCst);
+        id
     }
 }
 
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+///
+/// get_cpu_id faults until mp_apic_init has switched this cpu to x2APIC mode
+///
+pub fn local_apic_enabled() -> bool {
+    unsafe { msr::rdmsr(msr::IA32_APIC_BASE).get_bits(10..12) == 0b11 }
+}
+
 pub fn get_cpu_id() -> u32 {
     unsafe {
         let local_apic_id: u64 = msr::rdmsr(msr::IA32_X2APIC_APICID);
@@ -43,6 +62,23 @@ pub fn mp_abort_all() {
     }
 }
 
+pub const TLB_SHOOTDOWN_VEC: u8 = 61;
+
+///
+/// fixed delivery of `vec` to the cpu with x2APIC id `cpu`
+///
+pub fn send_ipi(cpu: u32, vec: u8) {
+    unsafe {
+        msr::wrmsr(msr::IA32_X2APIC_ICR, (cpu as u64) << 32 | 0x4000 | vec as u64);
+    }
+}
+
+pub fn eoi() {
+    unsafe {
+        msr::wrmsr(msr::IA32_X2APIC_EOI, 0);
+    }
+}
+
 pub fn mp_init_broadcast(entry_point: u64) {
     let vector_no: u64 = (entry_point >> 12) & 0xFF;
     unsafe {
@@ -1,3 +1,4 @@
+#[macro_use]
 pub mod serial;
 #[macro_use]
 pub mod vga;
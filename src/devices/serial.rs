@@ -1,6 +1,7 @@
 use x86::shared::{io, irq};
 use spin::Mutex;
 use interrupt::guard::InterruptGuard;
+use core::fmt;
 
 
 static SERIAL_LOCK: Mutex<()> = Mutex::new(());
@@ -29,6 +30,36 @@ pub fn write_char(a: char) {
     unsafe {io::outb(PORT, a as u8)}
 }
 
+#[macro_export]
+macro_rules! sprint {
+    ($($arg:tt)*) => ({
+        $crate::devices::serial::print(format_args!($($arg)*));
+    });
+}
+
+struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            write_char(c);
+        }
+        Ok(())
+    }
+}
+
+///
+/// formats straight to the port, never touches the heap
+///
+pub fn print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let iguard = InterruptGuard::disable_interrupt();
+    let g = SERIAL_LOCK.lock();
+    SerialWriter.write_fmt(args);
+    drop(g);
+    drop(iguard);
+}
+
 pub fn write_string(s: &str) {
     let guard = InterruptGuard::disable_interrupt();
     let g = SERIAL_LOCK.lock();
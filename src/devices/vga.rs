@@ -96,7 +96,8 @@ impl VgaWriter {
         VgaWriter {
             row: 0,
             col: 0,
-            buf: unsafe { Unique::new(0xb8000 as *mut _) },
+            // the text buffer at 0xb8000, through the direct map
+            buf: unsafe { Unique::new((::mem::paging::PHYS_MAP_BASE + 0xb8000) as *mut _) },
         }
     }
 
@@ -31,6 +31,7 @@ lazy_static! {
         idt.set_handler(14, exception_handler_errorcode!(page_fault_handler)).set_stack_index(2);
         idt.set_handler(60, exception_handler!(abort_handler));
         idt.set_handler(::devices::apic::TIMER_INTERRUPT_VEC, exception_handler!(timer_handler));
+        idt.set_handler(::devices::apic::TLB_SHOOTDOWN_VEC, exception_handler!(tlb_shootdown_handler));
 
         idt
     };
@@ -60,16 +61,70 @@ extern "C" fn gp_handler(fr: &ExceptionStackFrame, ec: u64) {
 }
 
 extern "C" fn page_fault_handler(fr: &ExceptionStackFrame, ec: u64) {
+    use mem::vma;
+    let addr = unsafe { ::x86::shared::control_regs::cr2() } as usize;
+    let err = match vma::handle_fault(addr, ec) {
+        // populated, retry the access
+        Ok(()) => return,
+        Err(e) => e,
+    };
+    if let Some(stack) = ::mem::stacks::guard_hit(addr) {
+        stack_overflow(fr, addr, &stack);
+        return;
+    }
+
     ::devices::vga::vga_force_unlock();
     ::devices::apic::mp_abort_all();
     //::devices::serial::write_string(fr.stack_pointer.to_string().as_str());
     one_fence!();
     kprint!("Page fault at rip = 0x{:x}\n", fr.instruction_pointer);
     kprint!("error_code: {} \n{:#?}\n", ec, fr);
-    kprint!("fault addr: 0x{:x}\n", unsafe {::x86::shared::control_regs::cr2()});
+    kprint!("fault addr: 0x{:x}, {} {} in {} mode\n", addr,
+            if ec & vma::FAULT_PRESENT != 0 { "protection violation" } else { "not present" },
+            if ec & vma::FAULT_FETCH != 0 { "on fetch" }
+            else if ec & vma::FAULT_WRITE != 0 { "on write" } else { "on read" },
+            if ec & vma::FAULT_USER != 0 { "user" } else { "kernel" });
+    kprint!("{:?}\n", err);
     loop {}
 }
 
+///
+/// A guard page was hit. A thread that ran off its own stack is killed
+/// once the handler returns, anything else stops the machine.
+///
+fn stack_overflow(fr: &ExceptionStackFrame, addr: usize, stack: &::mem::stacks::Stack) {
+    use mem::stacks::StackKind;
+    let sp = fr.stack_pointer as usize;
+    // rsp may already point into the guard page, anything else is a stray write
+    let own = sp >= stack.guard && sp <= stack.top;
+    let fatal = stack.kind != StackKind::Thread || !own;
+    if fatal {
+        ::devices::vga::vga_force_unlock();
+        ::devices::apic::mp_abort_all();
+        one_fence!();
+    }
+    kprint!("stack overflow in {} on CPU {}
+", stack, ::devices::apic::get_cpu_id());
+    kprint!("fault addr: 0x{:x}, rsp = 0x{:x}, rip = 0x{:x}
+", addr, sp, fr.instruction_pointer);
+    ::mem::stacks::backtrace(fr.instruction_pointer as usize, fr.registers.rbp, stack);
+    if fatal {
+        loop {}
+    }
+    kprint!("killing thread {}
+", stack.name());
+    // Not from here: this runs on the page fault IST stack, and a fault
+    // on the way through the scheduler would start over at its top, right
+    // on our live frames. Returning to the thread's own stack leaves the
+    // IST stack unused; what was on the thread stack is dead anyway and
+    // the stack is only freed once the scheduler is off it.
+    unsafe { fr.redirect(kill_overflowed as usize, stack.top - 8) };
+}
+
+extern "C" fn kill_overflowed() -> ! {
+    ::tasks::SCHEDULER.kill_current();
+}
+
 extern "C" fn default_handler(fr: &ExceptionStackFrame) {
     ::devices::apic::mp_abort_all();
     ::devices::serial::write_string("fuck?");
@@ -100,6 +155,10 @@ extern "C" fn abort_handler(fr: &ExceptionStackFrame) {
     unsafe { asm!("cli; hlt;") };
 }
 
+extern "C" fn tlb_shootdown_handler(fr: &ExceptionStackFrame) {
+    ::mem::shootdown::handle_ipi();
+}
+
 extern "C" fn timer_handler(fr: &ExceptionStackFrame) {
     //::devices::serial::write_char('!');
     unsafe {
@@ -222,6 +281,7 @@ struct TSSDescriptor {
 }
 
 const TSS_OFFSET_IN_GDT: usize = 24;
+const IST_PAGES: usize = 2;
 
 pub unsafe fn install_tss_table() {
     //kprint!("setting tss\n");
@@ -231,22 +291,25 @@ pub unsafe fn install_tss_table() {
         limit: size_of::<TaskStateSegment>() as u16 - 1,
         base_lo: (transmute::<_, usize>(tss) & 0xFFFF) as u16,
         base_mi: ((transmute::<_, usize>(tss)) >> 16 & 0xFF) as u8,
-        base_hi: ((transmute::<_, usize>(tss)) >> 32 & 0xFF) as u8,
+        base_hi: ((transmute::<_, usize>(tss)) >> 24 & 0xFF) as u8,
         flags: 0b0000000010001001
     };
     use super::gdt;
     let gdt_ref: &mut gdt::GDTController = gdt::GDT.0.get().as_mut().unwrap();
-    let tss_index = gdt_ref.add(transmute::<TSSDescriptor, u64>(descriptor));
+    let tss_index = gdt_ref.add(transmute::<TSSDescriptor, u64>(descriptor),
+                                (transmute::<_, usize>(tss) >> 32) as u64);
     gdt_ref.install();
     load_tr(SegmentSelector::new(tss_index as u16, PrivilegeLevel::Ring0))
 }
 
 pub fn get_tss_table<'a>() -> &'a TaskStateSegment {
-    let tss: &mut TaskStateSegment = unsafe { transmute(::mem::FRAME.alloc()) };
+    let tss: &mut TaskStateSegment = unsafe { transmute(::mem::paging::phys_to_virt(::mem::FRAME.alloc())) };
     *tss = TaskStateSegment::new();
-    tss.ist[0] = ::mem::FRAME.alloc_stack(2) as u64;
-    tss.ist[1] = ::mem::FRAME.alloc_stack(2) as u64;
-    tss.ist[2] = ::mem::FRAME.alloc_stack(2) as u64;
+    for i in 0..3 {
+        let top = ::mem::FRAME.alloc_stack(IST_PAGES);
+        ::mem::stacks::register(top, IST_PAGES, ::mem::stacks::StackKind::Ist(i), "");
+        tss.ist[i] = top as u64;
+    }
     //kprint!("ist[0] = 0x{:x}\n", tss.ist[0]);
     //kprint!("ist[1] = 0x{:x}\n", tss.ist[1]);
     //kprint!("ist[2] = 0x{:x}\n", tss.ist[2]);
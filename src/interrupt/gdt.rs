@@ -70,15 +70,17 @@ impl GDTController {
     }
 
     ///
-    /// adds a descriptor to GDT
+    /// adds a 16 byte system descriptor to GDT, `high` holds
+    /// the upper 32 bits of the base
     ///
-    pub fn add(&mut self, item: u64) -> usize {
+    pub fn add(&mut self, item: u64, high: u64) -> usize {
         let index = unsafe {
             ::core::intrinsics::atomic_xadd(&mut self.next_free, 2)
         };
         unsafe { ::core::intrinsics::atomic_fence(); }
-        assert!(index < GDT_SIZE);
+        assert!(index + 1 < GDT_SIZE);
         self.table[index] = item;
+        self.table[index + 1] = high;
         index
     }
 }
\ No newline at end of file
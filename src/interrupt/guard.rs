@@ -1,5 +1,6 @@
 use x86::shared::flags::*;
-use core::ops::Drop;
+use core::ops::{Drop, Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
 
 pub struct InterruptGuard {
     int_enabled: bool
@@ -8,7 +9,7 @@ pub struct InterruptGuard {
 impl InterruptGuard {
     pub fn disable_interrupt() -> InterruptGuard {
         let ret = InterruptGuard {
-            int_enabled: flags().contains(FLAGS_IF)
+            int_enabled: interrupts_enabled()
         };
         unsafe {
             asm!("cli" :::: "volatile");
@@ -26,4 +27,64 @@ impl Drop for InterruptGuard {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+pub fn interrupts_enabled() -> bool {
+    flags().contains(FLAGS_IF)
+}
+
+///
+/// A spin lock that keeps interrupts off on this cpu while it is held,
+/// so interrupt handlers can take it without spinning on a holder they
+/// interrupted. Keep the critical sections short and never wait for
+/// another cpu inside one, e.g. for a TLB shootdown.
+///
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+///
+/// Releases the lock first, then restores the interrupt flag
+///
+pub struct IrqMutexGuard<'a, T: 'a> {
+    guard: MutexGuard<'a, T>,
+    _int: InterruptGuard,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(val: T) -> IrqMutex<T> {
+        IrqMutex {
+            inner: Mutex::new(val)
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let int = InterruptGuard::disable_interrupt();
+        IrqMutexGuard {
+            guard: self.inner.lock(),
+            _int: int,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let int = InterruptGuard::disable_interrupt();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard { guard: guard, _int: int }),
+            None => None,
+        }
+    }
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
@@ -13,6 +13,19 @@ pub struct ExceptionStackFrame {
 
 }
 
+impl ExceptionStackFrame {
+    ///
+    /// makes the handler return to `rip` with `rsp` instead of to the
+    /// interrupted code. The frame is on the handler's stack, nobody
+    /// else looks at it.
+    ///
+    pub unsafe fn redirect(&self, rip: usize, rsp: usize) {
+        let fr = self as *const ExceptionStackFrame as *mut ExceptionStackFrame;
+        (*fr).instruction_pointer = rip as u64;
+        (*fr).stack_pointer = rsp as u64;
+    }
+}
+
 #[derive(Debug)]
 #[repr(C, packed)]
 pub struct ExceptionRegisters {
@@ -26,7 +39,7 @@ pub struct ExceptionRegisters {
     rcx: usize,
     rbx: usize,
     rax: usize,
-    rbp: usize
+    pub rbp: usize
 }
 
 
@@ -103,6 +116,7 @@ macro_rules! exception_handler_errorcode {
                       mov rdi, rsp
                       sub rsp, 8
                       call $0
+                      add rsp, 8
                       "
                       :: "i"($name as extern "C" fn(&ExceptionStackFrame, u64))
                       : "rdi", "rsi" : "intel", "volatile");
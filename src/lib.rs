@@ -20,6 +20,7 @@
 #![allow(non_snake_case)]
 #![allow(non_upper_case_globals)]
 #![feature(ptr_eq)]
+#![feature(integer_atomics)]
 #[macro_use]
 extern crate alloc;
 #[macro_use]
@@ -58,28 +59,46 @@ use containers::queue::Queue;
 use containers::cpu_local::CPULocal;
 use core::sync::atomic::*;
 
-
 #[no_mangle]
 pub extern "C" fn kmain(bootinfo: usize) {
     let mut vwriter = vga::VgaWriter::new();
     vwriter.clear();
     serial::init();
     kprint!("multiboot_info = {:x}\n", bootinfo);
+    let bootinfo = paging::phys_to_virt(bootinfo);
 
     // mem::parse_multiboot(bootinfo);
     unsafe { mem::BOOTINFO = bootinfo };
     mem::bitmap::test_bitmap();
+    mem::protect_kernel(bootinfo);
 
     use alloc::boxed::Box;
     let heap_test = Box::new(42);
+    mem::heap_allocator::test_alignment();
+    mem::heap_allocator::test_stats();
+    // both take all memory for a while
+    if selftest() {
+        mem::heap_allocator::test_oom();
+        mem::heap_allocator::test_atomic();
+    }
 
     descriptors::IDT.load();
+    mem::pcid::enable();
+    kprint!("paging: {} levels, PCIDs {}\n", paging::top_level() + 1,
+            if mem::pcid::enabled() { "on" } else { "off" });
 
     test_sse();
     test_mapping();
+    test_contiguous();
+    test_stack_frames();
+    test_demand_paging();
 
     let id = devices::apic::mp_apic_init();
     kprint!("cpu local id {}\n", id);
+    test_address_space();
+    test_cow();
+    test_huge_pages();
+    test_pcid();
 
     unsafe {
         //   int!(12);
@@ -97,17 +116,41 @@ pub extern "C" fn kmain(bootinfo: usize) {
             devices::apic::micro_delay(50 * 1000);
         }
     }
+    // every cpu runs in the upper half now
+    mem::address_space::KERNEL_SPACE.drop_identity_map();
+    test_direct_map();
+    test_mmio();
+    test_dma();
 
     kprint!("found {} PCI devices \n", devices::pci::PCI_DEVICES.len());
     // call this to initialize global AHCI
     //devices::ahci::global_HBA_status();
     kprint!("found {} block devices\n", fs::block::BLOCK_DEVICES.len());
+    // swap=<device>,<first sector>,<slots>, whatever is there gets overwritten
+    if let Some(arg) = mem::flag_value(bootinfo, "swap") {
+        match mem::swap::parse_config(arg) {
+            Some((device, first, slots)) if device < fs::block::BLOCK_DEVICES.len() =>
+                mem::swap::enable(device, first, slots),
+            _ => kprint!("swap: ignoring swap={}\n", arg),
+        }
+    }
+    if selftest() && mem::swap::enabled() {
+        test_swap();
+    }
     tasks::threads::new_thread(thread_test, "init");
     ::devices::apic::enable_timer();
 
     loop {}
 }
 
+///
+/// whether the command line asks for the tests that crash
+/// a thread on purpose or take all memory for a while
+///
+fn selftest() -> bool {
+    mem::has_flag(unsafe { mem::BOOTINFO }, "selftest")
+}
+
 lazy_static! {
     static ref clocal: CPULocal<usize> = CPULocal::create();
 }
@@ -127,6 +170,9 @@ fn thread_test(val: usize) -> usize {
     ::tasks::threads::new_thread(thread_test2, "Test2");
     ::tasks::threads::new_thread(thread_test2, "Test2");
     ::tasks::threads::new_thread(thread_test2, "Test2");
+    if selftest() {
+        ::tasks::threads::new_thread(test_stack_overflow, "overflow");
+    }
 
 
 
@@ -134,6 +180,15 @@ fn thread_test(val: usize) -> usize {
     0x1
 }
 
+// gets killed when it reaches its guard page, the other threads go on
+fn test_stack_overflow(depth: usize) -> usize {
+    let frame = [depth; 64];
+    if depth == ::core::usize::MAX {
+        return 0;
+    }
+    test_stack_overflow(depth + 1) + frame[depth % 64]
+}
+
 static i: AtomicUsize = ATOMIC_USIZE_INIT;
 
 fn thread_test2(val: usize) -> usize {
@@ -159,6 +214,7 @@ lazy_static! {
 
 #[no_mangle]
 pub extern "C" fn mp_main() {
+    mem::pcid::enable();
     unsafe { irq::enable() };
 
     let id = devices::apic::mp_apic_init();
@@ -186,6 +242,7 @@ pub extern "C" fn create_stack() -> usize {
     }
     descriptors::IDT.load();
     let ret = mem::FRAME.alloc_stack(2);
+    mem::stacks::register(ret, 2, mem::stacks::StackKind::Ap, "");
     // kprint!("stack = 0x{:x}\n", ret);
     ret
 
@@ -199,7 +256,7 @@ extern "C" {
 fn load_ap_bootstrap(addr: u64) {
     unsafe {
         let distance: usize = transmute::<_, usize>(&mp_end) - transmute::<_, usize>(&mp_start);
-        let ptr: *mut u8 = ::core::intrinsics::transmute(addr);
+        let ptr = paging::phys_to_virt(addr as usize) as *mut u8;
         rlibc::memmove(ptr, &mut mp_start, distance);
     }
 }
@@ -231,9 +288,241 @@ fn test_mapping() {
     }
 }
 
+fn test_demand_paging() {
+    let base = mem::FRAME.reserve(4);
+    let page = base + 2 * 4096;
+    assert_eq!(paging::translate(page), None);
+    unsafe {
+        assert_eq!(*(page as *const usize), 0);
+        *(page as *mut usize) = 0x600dfa11;
+        assert_eq!(*(page as *const usize), 0x600dfa11);
+    }
+    assert!(paging::translate(page).is_some());
+    assert!(paging::translate(base).is_none());
+    mem::FRAME.unreserve(base, 4);
+    assert_eq!(paging::translate(page), None);
+    kprint!("demand paging working\n");
+}
+
+///
+/// an empty address space for the tests below and a user address in it
+///
+fn test_space() -> (mem::address_space::AddressSpace, usize) {
+    (mem::address_space::AddressSpace::new(), 0x80_0000_0000)
+}
+
+fn test_address_space() {
+    use mem::address_space::KERNEL_SPACE;
+    let (space, vaddr) = test_space();
+    let frame = mem::FRAME.alloc();
+    space.map(vaddr, frame, paging::PRESENT | paging::WRITABLE);
+    assert_eq!(space.translate(vaddr + 8), Some(frame + 8));
+    assert_eq!(KERNEL_SPACE.translate(vaddr), None);
+
+    space.activate();
+    unsafe { *(vaddr as *mut usize) = 0x5a5a5a5a };
+    KERNEL_SPACE.activate();
+    assert_eq!(unsafe { *(paging::phys_to_virt(frame) as *const usize) }, 0x5a5a5a5a);
+
+    assert_eq!(space.unmap(vaddr), Some(frame));
+    drop(space);
+    mem::FRAME.dealloc(frame);
+    kprint!("address space test successful\n");
+}
+
+fn test_pcid() {
+    use mem::address_space::KERNEL_SPACE;
+    let (space, vaddr) = test_space();
+    let old = mem::FRAME.alloc();
+    let new = mem::FRAME.alloc();
+    unsafe {
+        *(paging::phys_to_virt(old) as *mut usize) = 1;
+        *(paging::phys_to_virt(new) as *mut usize) = 2;
+    }
+    space.map(vaddr, old, paging::PRESENT | paging::WRITABLE);
+    space.activate();
+    assert_eq!(unsafe { *(vaddr as *const usize) }, 1);
+    KERNEL_SPACE.activate();
+
+    // changed while it was not loaded, the entry cached under its PCID must not come back
+    space.unmap(vaddr);
+    space.map(vaddr, new, paging::PRESENT | paging::WRITABLE);
+    space.activate();
+    assert_eq!(unsafe { *(vaddr as *const usize) }, 2);
+    KERNEL_SPACE.activate();
+
+    space.unmap(vaddr);
+    drop(space);
+    mem::FRAME.dealloc(old);
+    mem::FRAME.dealloc(new);
+    kprint!("PCID test successful\n");
+}
+
+fn test_cow() {
+    use mem::vma::{self, Vma, VmaKind};
+    let (parent, vaddr) = test_space();
+    parent.add_vma(Vma::new(vaddr, vaddr + 4096, VmaKind::Anonymous, vma::READ | vma::WRITE));
+    parent.fault(vaddr, vma::FAULT_WRITE).unwrap();
+    let frame = parent.translate(vaddr).unwrap();
+    unsafe { *(paging::phys_to_virt(frame) as *mut usize) = 0xc0ffee };
+
+    let child = parent.clone_cow();
+    assert_eq!(child.translate(vaddr), Some(frame));
+    assert_eq!(mem::FRAME.ref_count(frame), 2);
+
+    // the child copies, the parent is left as the only owner and keeps the frame
+    child.fault(vaddr, vma::FAULT_PRESENT | vma::FAULT_WRITE).unwrap();
+    let copy = child.translate(vaddr).unwrap();
+    assert!(copy != frame);
+    assert_eq!(unsafe { *(paging::phys_to_virt(copy) as *const usize) }, 0xc0ffee);
+    assert_eq!(mem::FRAME.ref_count(frame), 1);
+    parent.fault(vaddr, vma::FAULT_PRESENT | vma::FAULT_WRITE).unwrap();
+    assert_eq!(parent.translate(vaddr), Some(frame));
+
+    drop(child);
+    drop(parent);
+    kprint!("copy on write working\n");
+}
+
+fn test_huge_pages() {
+    use mem::paging::PageSize;
+    let flags = paging::PRESENT | paging::WRITABLE;
+    let (space, vaddr) = test_space();
+    space.map_huge(vaddr, 0x200000, PageSize::Size2M, flags);
+    assert_eq!(space.translate(vaddr + 0x1234), Some(0x201234));
+
+    // remapping and unmapping single pages splits the huge page
+    let frame = mem::FRAME.alloc();
+    space.remap(vaddr + 0x3000, frame, flags);
+    space.unmap(vaddr + 0x5000);
+    assert_eq!(space.translate(vaddr + 0x3000), Some(frame));
+    assert_eq!(space.translate(vaddr + 0x4000), Some(0x204000));
+    assert_eq!(space.translate(vaddr + 0x5000), None);
+
+    let giant = 0x100_0000_0000;
+    space.map_huge(giant, 0, PageSize::Size1G, flags);
+    assert_eq!(space.translate(giant + 0x12345678), Some(0x12345678));
+    space.unmap_huge(giant, if paging::has_1g_pages() { PageSize::Size1G } else { PageSize::Size2M });
+
+    drop(space);
+    mem::FRAME.dealloc(frame);
+    kprint!("huge pages working\n");
+}
+
+fn test_direct_map() {
+    use mem::paging::{phys_to_virt, virt_to_phys, KERNEL_BASE};
+    let frame = mem::FRAME.alloc();
+    let vaddr = phys_to_virt(frame);
+    assert_eq!(virt_to_phys(vaddr + 8), frame + 8);
+    assert_eq!(paging::translate(vaddr), Some(frame));
+    // the kernel image and the lower half
+    let here = test_direct_map as usize;
+    assert!(here >= KERNEL_BASE);
+    assert_eq!(paging::translate(here), Some(here - KERNEL_BASE));
+    assert_eq!(paging::translate(0x100000), None);
+    mem::FRAME.dealloc(frame);
+    kprint!("direct map working\n");
+}
+
+fn test_mmio() {
+    use mem::paging::{map_mmio, CacheMode, NO_CACHE, WRITE_THROUGH};
+    // the vga text buffer, only the mapping is looked at
+    let region = map_mmio(0xb8000 + 0xff0, 0x20, CacheMode::WriteCombining);
+    let base = region.base();
+    assert_eq!(paging::translate(base), Some(0xb8ff0));
+    assert_eq!(paging::translate(base + 0x10), Some(0xb9000));
+    let (entry, _) = paging::get_entry(base).unwrap();
+    assert!(entry.flags().contains(NO_CACHE) && !entry.flags().contains(WRITE_THROUGH));
+    drop(region);
+    assert_eq!(paging::translate(base), None);
+    kprint!("mmio mapping working\n");
+}
+
+fn test_dma() {
+    use mem::dma::{DmaBuffer, Zone};
+    use mem::buddy::DMA32_LIMIT;
+    let free = mem::FRAME.free();
+    {
+        let buf = DmaBuffer::new(3 * 4096, Zone::Dma32).unwrap();
+        assert!(buf.bus_addr() + 4 * 4096 <= DMA32_LIMIT);
+        assert_eq!(buf.bus_addr() % 4096, 0);
+        assert_eq!(paging::virt_to_phys(buf.as_ptr() as usize), buf.bus_addr());
+        unsafe { assert_eq!(*buf.as_ptr().offset(3 * 4096 - 1), 0) };
+        assert_eq!(mem::FRAME.free(), free - 4);
+    }
+    assert_eq!(mem::FRAME.free(), free);
+    kprint!("dma buffers working\n");
+}
+
+fn test_contiguous() {
+    let free = mem::FRAME.free();
+    let a = mem::FRAME.alloc_order(9);
+    let b = mem::FRAME.alloc_order(3);
+    assert_eq!(a % (4096 << 9), 0);
+    assert_eq!(b % (4096 << 3), 0);
+    mem::FRAME.dealloc_order(a, 9);
+    mem::FRAME.dealloc_order(b, 3);
+    assert_eq!(mem::FRAME.free(), free);
+    kprint!("buddy allocator working\n");
+}
+
+// a freed stack gives all of its frames back
+fn test_stack_frames() {
+    // the first one may leave new page tables behind for its range
+    let top = mem::FRAME.alloc_stack(4);
+    mem::FRAME.dealloc_stack(top, 4);
+
+    let free = mem::FRAME.free();
+    let top = mem::FRAME.alloc_stack(4);
+    assert_eq!(mem::FRAME.free(), free - 4);
+    assert_eq!(paging::translate(top - 5 * 4096), None);
+    for i in 1..5 {
+        assert!(paging::translate(top - i * 4096).is_some());
+        unsafe { *((top - i * 4096) as *mut usize) = i };
+    }
+    mem::FRAME.dealloc_stack(top, 4);
+    assert_eq!(paging::translate(top - 4096), None);
+    assert_eq!(mem::FRAME.free(), free);
+    kprint!("stack frames test successful\n");
+}
+
+fn test_swap() {
+    use mem::vma::{self, Vma, VmaKind};
+    let (space, vaddr) = test_space();
+    let pages = 4;
+    space.add_vma(Vma::new(vaddr, vaddr + pages * 4096, VmaKind::Anonymous, vma::READ | vma::WRITE));
+    for i in 0..pages {
+        space.fault(vaddr + i * 4096, vma::FAULT_WRITE).unwrap();
+        let frame = space.translate(vaddr + i * 4096).unwrap();
+        unsafe { *(paging::phys_to_virt(frame + 8 * i) as *mut usize) = 0x5a5a0000 + i };
+    }
+
+    let free = mem::FRAME.free();
+    let used = mem::swap::used();
+    assert!(space.swap_out(vaddr));
+    assert_eq!(space.translate(vaddr), None);
+    assert_eq!(mem::FRAME.free(), free + 1);
+    // nothing went through the mappings, so none of them is marked accessed
+    assert_eq!(space.reclaim(pages), pages - 1);
+    assert_eq!(mem::swap::used(), used + pages);
+
+    for i in 0..pages {
+        space.fault(vaddr + i * 4096, 0).unwrap();
+        let frame = space.translate(vaddr + i * 4096).unwrap();
+        assert_eq!(unsafe { *(paging::phys_to_virt(frame + 8 * i) as *const usize) }, 0x5a5a0000 + i);
+    }
+    assert_eq!(mem::swap::used(), used);
+
+    // a page still in swap gives its slot back with the space
+    assert!(space.swap_out(vaddr));
+    drop(space);
+    assert_eq!(mem::swap::used(), used);
+    kprint!("swap test successful\n");
+}
+
 fn test_parallel_block() {
     let block = &fs::block::BLOCK_DEVICES[0];
-    let test: &mut usize = unsafe { (mem::FRAME.alloc() as *mut usize).as_mut().unwrap() };
+    let test: &mut usize = unsafe { (paging::phys_to_virt(mem::FRAME.alloc()) as *mut usize).as_mut().unwrap() };
     for x in 0..10000 {
         unsafe {
             *test = x;
@@ -290,7 +579,18 @@ pub extern "C" fn malloc(size: usize) -> *mut u8 {
     mem::alloc_stub::__rust_allocate(size, 16)
 }
 
+const EINVAL: i32 = 22;
+const ENOMEM: i32 = 12;
+
 #[no_mangle]
-pub extern "C" fn posix_memalign(size: usize, align: usize) -> *mut u8 {
-    mem::alloc_stub::__rust_allocate(size, align)
+pub extern "C" fn posix_memalign(memptr: *mut *mut u8, align: usize, size: usize) -> i32 {
+    if !align.is_power_of_two() || align % core::mem::size_of::<usize>() != 0 {
+        return EINVAL;
+    }
+    let ptr = mem::alloc_stub::__rust_allocate(size, align);
+    if ptr.is_null() {
+        return ENOMEM;
+    }
+    unsafe { *memptr = ptr };
+    0
 }
@@ -9,19 +9,36 @@
 
 global long_mode_start
 extern kmain
+extern stack_top
+extern gdt_pointer
 
-section .text
+; physical memory is mapped linearly from here
+PHYS_MAP_BASE equ 0xFFFF800000000000
+
+; reached through the identity map, like the rest of the boot code
+section .boot progbits alloc exec nowrite
 bits 64
 long_mode_start:
+    mov rax, higher_half_start
+    jmp rax
+
+section .text
+bits 64
+higher_half_start:
+    ; same stack and GDT as before, through their upper half addresses
+    mov rsp, stack_top
+    mov rax, gdt_pointer
+    lgdt [rax]
     call kmain
 .os_returned:
     ; rust main returned, print `OS returned!`
+    mov rdi, PHYS_MAP_BASE + 0xb8000
     mov rax, 0x4f724f204f534f4f
-    mov [0xb8000], rax
+    mov [rdi], rax
     mov rax, 0x4f724f754f744f65
-    mov [0xb8008], rax
+    mov [rdi + 8], rax
     mov rax, 0x4f214f644f654f6e
-    mov [0xb8010], rax
+    mov [rdi + 16], rax
     hlt
 
 
@@ -0,0 +1,762 @@
+use x86::shared::control_regs::*;
+use x86::shared::tlb;
+use spin::{Mutex, MutexGuard};
+use interrupt::guard::IrqMutex;
+use collections::vec::Vec;
+use devices::apic;
+use super::paging::*;
+use super::vma::*;
+use super::FRAME;
+use super::shootdown;
+use super::swap;
+use super::oom::OutOfMemory;
+use super::pcid;
+use core::sync::atomic::*;
+use rlibc::memcpy;
+
+// top level slots from here on are the kernel's and shared by every
+// address space, the lower half belongs to user space
+const KERNEL_HIGH_SLOTS: usize = 256;
+
+lazy_static! {
+    ///
+    /// the page tables set up by boot.asm
+    ///
+    pub static ref KERNEL_SPACE: AddressSpace = {
+        let space = AddressSpace {
+            pml4: unsafe { cr3() } as usize & ADDRESS_MASK,
+            kernel: true,
+            vmas: Mutex::new(Vec::new()),
+            pcid: 0,
+            stale: AtomicU64::new(0),
+        };
+        space.fill_kernel_slots();
+        space
+    };
+}
+
+// address space loaded on each cpu, 0 while it runs on the kernel tables.
+// Frees in interrupt handlers read it for their shootdowns.
+static ACTIVE: IrqMutex<[usize; apic::MAX_CPU]> = IrqMutex::new([0; apic::MAX_CPU]);
+
+///
+/// A page table hierarchy. Mappings can be edited whether or not it is
+/// loaded anywhere. The kernel slots of the top level table point at
+/// the kernel's own tables, so kernel mappings show up everywhere.
+///
+/// An active address space must not move, `current` hands out
+/// references to it.
+///
+pub struct AddressSpace {
+    // the top level table, a PML5 with five-level paging
+    pml4: usize,
+    kernel: bool,
+    // sorted by start, taken through lock_vmas
+    vmas: Mutex<Vec<Vma>>,
+    // tags its TLB entries, 0 if it has none of its own
+    pcid: usize,
+    // cpus that may still hold outdated entries tagged with pcid
+    stale: AtomicU64,
+}
+
+///
+/// the NX bit is reserved while EFER.NXE is off
+///
+fn sanitize(flags: EntryFlags) -> EntryFlags {
+    if nx_enabled() { flags } else { flags - NO_EXECUTE }
+}
+
+fn is_kernel_slot(slot: usize) -> bool {
+    slot >= KERNEL_HIGH_SLOTS
+}
+
+// the boot cpu is 0 until its local apic is up
+fn current_cpu() -> usize {
+    if apic::local_apic_enabled() { apic::get_cpu_id() as usize } else { 0 }
+}
+
+impl AddressSpace {
+    ///
+    /// a new space sharing the kernel half with KERNEL_SPACE
+    ///
+    pub fn new() -> AddressSpace {
+        let pml4 = create_table();
+        let kernel = unsafe { get_table(KERNEL_SPACE.pml4) };
+        let table = unsafe { get_table(pml4) };
+        for slot in 0..512 {
+            if is_kernel_slot(slot) {
+                table[slot] = kernel[slot];
+            }
+        }
+        AddressSpace {
+            pml4: pml4,
+            kernel: false,
+            vmas: Mutex::new(Vec::new()),
+            pcid: pcid::alloc(),
+            // the last owner of the PCID may have left entries anywhere
+            stale: AtomicU64::new(!0),
+        }
+    }
+
+    ///
+    /// the space loaded on this cpu
+    ///
+    pub fn current() -> &'static AddressSpace {
+        let active = ACTIVE.lock();
+        match active[current_cpu()] {
+            0 => &*KERNEL_SPACE,
+            p => unsafe { &*(p as *const AddressSpace) },
+        }
+    }
+
+    pub fn is_kernel_address(vaddr: usize) -> bool {
+        is_kernel_slot(get_index(vaddr, top_level()))
+    }
+
+    ///
+    /// physical address of the top level table
+    ///
+    pub fn pml4(&self) -> usize {
+        self.pml4
+    }
+
+    pub fn map(&self, vaddr: usize, paddr: usize, flags: EntryFlags) -> Option<usize> {
+        self.map_huge(vaddr, paddr, PageSize::Size4K, flags)
+    }
+
+    pub fn map_prot(&self, vaddr: usize, paddr: usize, prot: Protection, cache: CacheMode) -> Option<usize> {
+        self.map(vaddr, paddr, protection_flags(prot, cache))
+    }
+
+    ///
+    /// changes the permissions of the mapped 4 KiB page at `vaddr`,
+    /// splitting a huge page around it. False if nothing is mapped there.
+    ///
+    pub fn protect(&self, vaddr: usize, prot: Protection, cache: CacheMode) -> bool {
+        self.check(vaddr);
+        let entry = match lookup(self.pml4, vaddr) {
+            None => return false,
+            Some((entry, PageSize::Size4K)) => entry,
+            Some(_) => walk(self.pml4, vaddr, 0, true, prot.contains(USER)).unwrap(),
+        };
+        let mut flags = protection_flags(prot, cache);
+        if entry.flags().contains(COPY_ON_WRITE) {
+            // stays read-only until the write fault copies it
+            flags = (flags - WRITABLE) | COPY_ON_WRITE;
+        }
+        entry.set_flags(flags);
+        self.shootdown(vaddr, 1);
+        true
+    }
+
+    ///
+    /// maps a page of `size`, both addresses aligned to it. 1 GiB pages
+    /// are mapped as 2 MiB pages on cpus without support for them.
+    ///
+    pub fn map_huge(&self, vaddr: usize, paddr: usize, size: PageSize, flags: EntryFlags) -> Option<usize> {
+        assert!(vaddr % size.bytes() == 0 && paddr % size.bytes() == 0,
+                "misaligned mapping 0x{:x} -> 0x{:x}", vaddr, paddr);
+        self.check(vaddr);
+        if size == PageSize::Size1G && !has_1g_pages() {
+            let step = PageSize::Size2M.bytes();
+            for i in 0..size.bytes() / step {
+                self.map_huge(vaddr + i * step, paddr + i * step, PageSize::Size2M, flags);
+            }
+            return Some(vaddr);
+        }
+        let user = flags.contains(USER_ACCESSIBLE);
+        let entry = walk(self.pml4, vaddr, size.level(), true, user).unwrap();
+        if entry.is_present() {
+            panic!("vaddr already in use. vaddr = {:x}", vaddr);
+        }
+        let mut flags = sanitize(flags) | PRESENT;
+        if size != PageSize::Size4K {
+            flags = flags | HUGE_PAGE;
+        }
+        entry.clear();
+        entry.set_paddr(paddr);
+        entry.set_flags(flags);
+        self.flush(vaddr);
+        Some(vaddr)
+    }
+
+    ///
+    /// maps the 4 KiB page at `vaddr` whatever was there before,
+    /// a huge page covering it is split
+    ///
+    pub fn remap(&self, vaddr: usize, paddr: usize, flags: EntryFlags) {
+        self.check(vaddr);
+        let user = flags.contains(USER_ACCESSIBLE);
+        let entry = walk(self.pml4, vaddr, 0, true, user).unwrap();
+        entry.clear();
+        entry.set_paddr(paddr);
+        entry.set_flags(sanitize(flags) | PRESENT);
+        self.shootdown(vaddr, 1);
+    }
+
+    ///
+    /// unmaps the 4 KiB page at `vaddr`, splitting a huge page around it.
+    /// Returns the frame that was mapped, the frame itself is not freed.
+    ///
+    pub fn unmap(&self, vaddr: usize) -> Option<usize> {
+        let ret = self.unmap_deferred(vaddr);
+        if ret.is_some() {
+            self.shootdown(vaddr, 1);
+        }
+        ret
+    }
+
+    ///
+    /// like unmap, but leaves the TLB alone. The caller has to call
+    /// `shootdown` on the range before the frame is reused.
+    ///
+    pub fn unmap_deferred(&self, vaddr: usize) -> Option<usize> {
+        let entry = match lookup(self.pml4, vaddr) {
+            None => return None,
+            Some((entry, PageSize::Size4K)) => entry,
+            Some(_) => walk(self.pml4, vaddr, 0, true, false).unwrap(),
+        };
+        let paddr = entry.paddr();
+        entry.clear();
+        Some(paddr)
+    }
+
+    ///
+    /// unmaps a whole page of `size` mapped by map_huge
+    ///
+    pub fn unmap_huge(&self, vaddr: usize, size: PageSize) -> Option<usize> {
+        match lookup(self.pml4, vaddr) {
+            Some((entry, s)) => {
+                assert!(s == size, "0x{:x} is mapped as {:?}", vaddr, s);
+                let paddr = entry.paddr() & !(size.bytes() - 1);
+                entry.clear();
+                self.shootdown(vaddr, size.bytes() / 4096);
+                Some(paddr)
+            },
+            None => None,
+        }
+    }
+
+    ///
+    /// the present entry mapping `vaddr` and the size of its page
+    ///
+    pub fn entry<'a>(&self, vaddr: usize) -> Option<(&'a mut Entry, PageSize)> {
+        lookup(self.pml4, vaddr)
+    }
+
+    pub fn translate(&self, vaddr: usize) -> Option<usize> {
+        match lookup(self.pml4, vaddr) {
+            None => None,
+            Some((e, size)) => {
+                let mask = size.bytes() - 1;
+                Some((e.paddr() & !mask) | (vaddr & mask))
+            },
+        }
+    }
+
+    ///
+    /// loads this space on the current cpu. With PCIDs its TLB entries
+    /// from the last time survive unless they went stale since.
+    ///
+    pub fn activate(&self) {
+        let cpu = apic::get_cpu_id() as usize;
+        let mut active = ACTIVE.lock();
+        active[cpu] = if self.kernel { 0 } else { self as *const AddressSpace as usize };
+        let mut cr3 = self.pml4 as u64;
+        if pcid::enabled() {
+            cr3 |= self.pcid as u64;
+            let me = 1 << cpu;
+            // PCID 0 is shared, whatever it tagged may belong to somebody else
+            if self.pcid != 0 && self.stale.fetch_and(!me, Ordering::SeqCst) & me == 0 {
+                cr3 |= pcid::CR3_NOFLUSH;
+            }
+        }
+        unsafe { cr3_write(cr3) };
+    }
+
+    ///
+    /// bit n is set if cpu n has this space loaded
+    ///
+    pub fn active_cpus(&self) -> u64 {
+        let active = ACTIVE.lock();
+        let mut ret = 0;
+        for cpu in 0..apic::MAX_CPU {
+            // kernel mappings are live on every cpu, whatever it has loaded
+            if self.kernel || active[cpu] == self as *const AddressSpace as usize {
+                ret |= 1 << cpu;
+            }
+        }
+        ret
+    }
+
+    ///
+    /// Fork style copy: the VMAs are duplicated and every populated page
+    /// in them ends up shared copy-on-write by both spaces. Pages mapped
+    /// outside of a VMA are not copied.
+    ///
+    pub fn clone_cow(&self) -> AddressSpace {
+        assert!(!self.kernel);
+        let child = AddressSpace::new();
+        let vmas = self.lock_vmas();
+        for vma in vmas.iter() {
+            child.lock_vmas().push(*vma);
+            match vma.kind {
+                VmaKind::Anonymous | VmaKind::ZeroFill => {},
+                // populated again on the child's first access
+                _ => continue,
+            }
+            let mut page = vma.start;
+            while page < vma.end {
+                // swap slots have a single owner, bring the page back to share it
+                if let Some(entry) = leaf(self.pml4, page) {
+                    // the writer does not need our lock to finish
+                    while entry.swap_busy() {
+                        unsafe { asm!("pause" :::: "volatile") };
+                    }
+                    if let Some(slot) = entry.swap_slot() {
+                        self.swap_in(page, entry, slot).expect("OOM! bringing a page back from swap for clone_cow");
+                    }
+                }
+                if let Some((entry, PageSize::Size4K)) = self.entry(page) {
+                    let paddr = entry.paddr();
+                    let mut flags = entry.flags();
+                    if flags.contains(WRITABLE) {
+                        flags = (flags - WRITABLE) | COPY_ON_WRITE;
+                        entry.set_flags(flags);
+                        self.shootdown(page, 1);
+                    }
+                    FRAME.get_ref(paddr);
+                    child.map(page, paddr, flags);
+                }
+                page += 4096;
+            }
+        }
+        child
+    }
+
+    ///
+    /// registers a range to be populated on demand
+    ///
+    pub fn add_vma(&self, vma: Vma) {
+        self.check(vma.start);
+        self.check(vma.end - 1);
+        let mut vmas = self.lock_vmas();
+        let mut pos = 0;
+        while pos < vmas.len() && vmas[pos].start < vma.start {
+            pos += 1;
+        }
+        for other in vmas.iter() {
+            assert!(!other.overlaps(&vma), "vma 0x{:x} overlaps 0x{:x}", vma.start, other.start);
+        }
+        vmas.insert(pos, vma);
+    }
+
+    ///
+    /// removes the VMA starting at `start` and unmaps what it populated
+    ///
+    pub fn remove_vma(&self, start: usize) -> Option<Vma> {
+        let mut vmas = self.lock_vmas();
+        let pos = match vmas.iter().position(|v| v.start == start) {
+            Some(p) => p,
+            None => return None,
+        };
+        let vma = vmas.remove(pos);
+        vma.depopulate(self);
+        Some(vma)
+    }
+
+    pub fn find_vma(&self, vaddr: usize) -> Option<Vma> {
+        let vmas = self.lock_vmas();
+        for vma in vmas.iter() {
+            if vma.contains(vaddr) {
+                return Some(*vma);
+            }
+        }
+        None
+    }
+
+    ///
+    /// populates the page holding `vaddr` if the VMA covering it
+    /// allows the access described by the page fault error `code`
+    ///
+    pub fn fault(&self, vaddr: usize, code: u64) -> Result<(), FaultError> {
+        // held while populating so two cpus do not map the same page
+        let vmas = self.lock_vmas();
+        if code & FAULT_PRESENT != 0 && code & FAULT_WRITE != 0 {
+            if let Some((entry, PageSize::Size4K)) = self.entry(vaddr) {
+                if entry.flags().contains(COPY_ON_WRITE) {
+                    if self.break_cow(vaddr, entry).is_ok() {
+                        return Ok(());
+                    }
+                    drop(vmas);
+                    if self.reclaim(1) > 0 {
+                        return Ok(());
+                    }
+                    return Err(FaultError::OutOfMemory);
+                }
+            }
+        }
+        let vma = match vmas.iter().find(|v| v.contains(vaddr)) {
+            Some(v) => *v,
+            None => return Err(FaultError::NoVma),
+        };
+        if vma.kind == VmaKind::Guard {
+            return Err(FaultError::Guard(vma));
+        }
+        if code & FAULT_PRESENT != 0 || !vma.allows(code) {
+            return Err(FaultError::Protection(vma));
+        }
+        if let Some(entry) = leaf(self.pml4, vaddr) {
+            if entry.swap_busy() {
+                // still being written out, the access faults again until it is
+                return Ok(());
+            }
+            if let Some(slot) = entry.swap_slot() {
+                if self.swap_in(vaddr & !0xfff, entry, slot).is_ok() {
+                    return Ok(());
+                }
+                // the shrink hooks could not page anything out of this
+                // space while we held its VMAs, try again without them
+                drop(vmas);
+                if self.reclaim(1) > 0 {
+                    return Ok(());
+                }
+                return Err(FaultError::OutOfMemory);
+            }
+        }
+        if self.translate(vaddr).is_none() && vma.populate(self, vaddr, code).is_err() {
+            // same as for swap_in, the access faults again once there is room
+            drop(vmas);
+            if self.reclaim(1) > 0 {
+                return Ok(());
+            }
+            return Err(FaultError::OutOfMemory);
+        }
+        Ok(())
+    }
+
+    ///
+    /// reads the page at `page` back from swap into a new frame,
+    /// the page stays in swap if there is none
+    ///
+    fn swap_in(&self, page: usize, entry: &mut Entry, slot: usize) -> Result<(), OutOfMemory> {
+        let frame = FRAME.try_alloc()?;
+        swap::read_page(slot, frame);
+        swap::free_slot(slot);
+        let flags = (entry.flags() - SWAPPED) | PRESENT;
+        entry.set_paddr(frame);
+        entry.set_flags(flags);
+        self.flush(page);
+        Ok(())
+    }
+
+    ///
+    /// Writes the page at `vaddr` to swap and frees its frame. Only user
+    /// pages of swappable VMAs that are not shared with anybody qualify.
+    /// Returns whether it was paged out.
+    ///
+    pub fn swap_out(&self, vaddr: usize) -> bool {
+        if self.kernel {
+            return false;
+        }
+        let page = vaddr & !0xfff;
+        let claim = {
+            let vmas = self.lock_vmas();
+            match vmas.iter().find(|v| v.contains(vaddr)) {
+                Some(v) if v.swappable() => self.claim_swap_out(page),
+                _ => None,
+            }
+        };
+        match claim {
+            Some((slot, paddr)) => {
+                self.finish_swap_out(page, slot, paddr);
+                true
+            },
+            None => false,
+        }
+    }
+
+    ///
+    /// Unmaps the page and gives it a swap slot, returns the slot and the
+    /// frame. The entry is marked busy until finish_swap_out has written
+    /// the frame, so fault() cannot read the slot before. Needs the VMAs.
+    ///
+    fn claim_swap_out(&self, page: usize) -> Option<(usize, usize)> {
+        let entry = match self.entry(page) {
+            Some((e, PageSize::Size4K)) => e,
+            _ => return None,
+        };
+        let paddr = entry.paddr();
+        if paddr == FRAME.zero_page() || FRAME.ref_count(paddr) != 1 {
+            return None;
+        }
+        let slot = match swap::alloc_slot() {
+            Some(s) => s,
+            None => return None,
+        };
+        // nobody can write to it any more once the TLBs are clean
+        entry.set_swapped(slot);
+        let flags = entry.flags() | SWAP_IO;
+        entry.set_flags(flags);
+        self.shootdown(page, 1);
+        Some((slot, paddr))
+    }
+
+    ///
+    /// writes out a page claimed by claim_swap_out. Runs without the VMAs,
+    /// the entry is only changed atomically; if the page went away while
+    /// it was written, its slot is given back here.
+    ///
+    fn finish_swap_out(&self, page: usize, slot: usize, paddr: usize) {
+        swap::write_page(slot, paddr);
+        FRAME.put_ref(paddr);
+        let done = match leaf(self.pml4, page) {
+            Some(entry) => {
+                let old: Entry = *entry;
+                old.swap_busy() && old.swap_slot() == Some(slot)
+                    && entry.compare_and_set(old.to_int(), old.to_int() & !SWAP_IO.bits())
+            },
+            None => false,
+        };
+        if !done {
+            swap::free_slot(slot);
+        }
+    }
+
+    ///
+    /// Pages out up to `frames` pages of swappable VMAs. Pages used since
+    /// the last pass only lose their accessed bit. The VMAs are let go
+    /// during the I/O. Returns how many went.
+    ///
+    pub fn reclaim(&self, frames: usize) -> usize {
+        if self.kernel || !swap::enabled() {
+            return 0;
+        }
+        let mut freed = 0;
+        // the second round gets the pages that were only marked in the first
+        for _ in 0..2 {
+            let mut cursor = 0;
+            while freed < frames {
+                // the allocation that needs the memory may come from fault()
+                let claim = match self.vmas.try_lock() {
+                    Some(vmas) => self.next_victim(&vmas, &mut cursor),
+                    None => return freed,
+                };
+                match claim {
+                    Some((page, slot, paddr)) => {
+                        self.finish_swap_out(page, slot, paddr);
+                        freed += 1;
+                    },
+                    None => break,
+                }
+            }
+            if freed >= frames {
+                break;
+            }
+        }
+        freed
+    }
+
+    ///
+    /// claims the first page from `cursor` up that was not used since the
+    /// last pass, those that were lose their accessed bit on the way.
+    /// `cursor` ends up right after the page.
+    ///
+    fn next_victim(&self, vmas: &Vec<Vma>, cursor: &mut usize) -> Option<(usize, usize, usize)> {
+        loop {
+            // in address order, the list itself is not sorted
+            let vma = match vmas.iter().filter(|v| v.swappable() && v.end > *cursor).min_by_key(|v| v.start) {
+                Some(v) => *v,
+                None => return None,
+            };
+            let mut page = if vma.start > *cursor { vma.start } else { *cursor };
+            while page < vma.end {
+                *cursor = page + 4096;
+                if let Some((entry, PageSize::Size4K)) = self.entry(page) {
+                    if entry.flags().contains(ACCESSED) {
+                        let flags = entry.flags() - ACCESSED;
+                        entry.set_flags(flags);
+                    } else if let Some((slot, paddr)) = self.claim_swap_out(page) {
+                        return Some((page, slot, paddr));
+                    }
+                }
+                page += 4096;
+            }
+            *cursor = vma.end;
+        }
+    }
+
+    ///
+    /// clears the entry of a page that is in swap and returns its slot,
+    /// the slot itself is not freed. A page that is still being written
+    /// out gives None, its writer frees the slot.
+    ///
+    pub fn take_swapped(&self, vaddr: usize) -> Option<usize> {
+        let entry = match leaf(self.pml4, vaddr) {
+            Some(e) => e,
+            None => return None,
+        };
+        loop {
+            let old: Entry = *entry;
+            let slot = match old.swap_slot() {
+                Some(s) => s,
+                None => return None,
+            };
+            if entry.compare_and_set(old.to_int(), 0) {
+                // still being written out, finish_swap_out frees it
+                return if old.flags().contains(SWAP_IO) { None } else { Some(slot) };
+            }
+        }
+    }
+
+    ///
+    /// gives the page holding `vaddr` a frame of its own,
+    /// the last owner of a frame just takes it over. Without a frame
+    /// the entry is left as it is.
+    ///
+    fn break_cow(&self, vaddr: usize, entry: &mut Entry) -> Result<(), OutOfMemory> {
+        let page = vaddr & !0xfff;
+        let old = entry.paddr();
+        let flags = (entry.flags() - COPY_ON_WRITE) | WRITABLE;
+        if old != FRAME.zero_page() && FRAME.ref_count(old) == 1 {
+            entry.set_flags(flags);
+            self.shootdown(page, 1);
+            return Ok(());
+        }
+        let new = FRAME.try_alloc()?;
+        unsafe {
+            if old == FRAME.zero_page() {
+                ::rlibc::memset(phys_to_virt(new) as *mut u8, 0, 4096);
+            } else {
+                memcpy(phys_to_virt(new) as *mut u8, phys_to_virt(old) as *const u8, 4096);
+            }
+        }
+        entry.set_paddr(new);
+        entry.set_flags(flags);
+        self.shootdown(page, 1);
+        FRAME.put_ref(old);
+        Ok(())
+    }
+
+    ///
+    /// gives every kernel slot a table up front, the slots are copied
+    /// into new spaces and later additions would not show up there
+    ///
+    fn fill_kernel_slots(&self) {
+        let pml4 = unsafe { get_table(self.pml4) };
+        for slot in KERNEL_HIGH_SLOTS..512 {
+            if !pml4[slot].is_present() {
+                pml4[slot].set_paddr(create_table());
+                pml4[slot].set_flags(PRESENT | WRITABLE);
+            }
+        }
+    }
+
+    ///
+    /// Removes the identity map of low memory that boot.asm set up for
+    /// itself and the AP trampoline. Only the kernel space has it, and
+    /// it must not be used once every cpu runs in the upper half.
+    ///
+    pub fn drop_identity_map(&self) {
+        assert!(self.kernel);
+        let pml4 = unsafe { get_table(self.pml4) };
+        if five_level() {
+            // `pml4` is the P5 here. boot.asm points its first and last
+            // slot at the same P4, the kernel keeps using it through the
+            // last one, so that P4 loses its identity slot as well.
+            assert!(pml4[0].paddr() == pml4[511].paddr(), "P5 slots 0 and 511 do not share a P4");
+            let p4 = unsafe { get_table(pml4[511].paddr()) };
+            p4[0].clear();
+        }
+        pml4[0].clear();
+        self.shootdown(0, BOOT_DIRECT_MAP / 4096);
+    }
+
+    fn is_active_here(&self) -> bool {
+        self.kernel || unsafe { cr3() } as usize & ADDRESS_MASK == self.pml4
+    }
+
+    ///
+    /// for entries that were not present before, nobody can have them cached
+    ///
+    fn flush(&self, vaddr: usize) {
+        if self.is_active_here() {
+            unsafe { tlb::flush(vaddr) };
+        }
+    }
+
+    ///
+    /// flushes `pages` pages at `vaddr` on every cpu using this space
+    ///
+    pub fn shootdown(&self, vaddr: usize, pages: usize) {
+        if !self.kernel && pcid::enabled() {
+            // cpus that ran it before keep its entries under its PCID,
+            // they start from scratch when they load it again
+            let me = if self.is_active_here() { 1 << current_cpu() } else { 0 };
+            self.stale.fetch_or(!me, Ordering::SeqCst);
+        }
+        shootdown::shootdown(self.active_cpus(), vaddr, pages);
+    }
+
+    ///
+    /// Takes the VMAs. Their holder may be in a TLB shootdown, waiting
+    /// for this cpu while the page fault handler spins here with
+    /// interrupts off, so keep answering shootdowns until we get them.
+    ///
+    fn lock_vmas(&self) -> MutexGuard<Vec<Vma>> {
+        let mut guard = self.vmas.try_lock();
+        while guard.is_none() {
+            shootdown::service();
+            unsafe { asm!("pause" :::: "volatile") };
+            guard = self.vmas.try_lock();
+        }
+        guard.unwrap()
+    }
+
+    fn check(&self, vaddr: usize) {
+        if !self.kernel {
+            let slot = get_index(vaddr, top_level());
+            assert!(!is_kernel_slot(slot), "0x{:x} belongs to the kernel", vaddr);
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    ///
+    /// frees every table below the user slots and the top level table.
+    /// Whatever is still mapped is not freed, that is up to the owner.
+    ///
+    fn drop(&mut self) {
+        assert!(!self.kernel);
+        assert!(self.active_cpus() == 0, "dropping an active address space");
+        for vma in self.lock_vmas().iter() {
+            vma.depopulate(self);
+        }
+        let pml4 = unsafe { get_table(self.pml4) };
+        for slot in 0..512 {
+            if is_kernel_slot(slot) || !pml4[slot].is_present() {
+                continue;
+            }
+            free_table(pml4[slot].paddr(), top_level() - 1);
+            pml4[slot].clear();
+        }
+        FRAME.dealloc(self.pml4);
+        pcid::free(self.pcid);
+    }
+}
+
+///
+/// frees the table at `paddr` and the tables below it,
+/// `level` 0 is a page table
+///
+fn free_table(paddr: usize, level: u8) {
+    if level > 0 {
+        let table = unsafe { get_table(paddr) };
+        for entry in table.iter() {
+            if entry.is_present() && !entry.flags().contains(HUGE_PAGE) {
+                free_table(entry.paddr(), level - 1);
+            }
+        }
+    }
+    FRAME.dealloc(paddr);
+}
@@ -1,19 +1,29 @@
 use super::heap_allocator::HEAP;
+use super::track;
 use core::intrinsics::transmute;
 use core::mem::size_of;
 use core::ptr::null_mut;
 use rlibc::memmove;
 
 #[no_mangle]
-pub extern fn __rust_allocate(size: usize, _align: usize) -> *mut u8 {
-    unsafe {transmute(HEAP.allocate(size))}
+pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
+    let ret = match HEAP.try_allocate(size, align) {
+        Ok(ret) => ret,
+        Err(_) => return null_mut(),
+    };
+    if track::enabled() {
+        track::record(ret, size, track::callers());
+    }
+    unsafe {transmute(ret)}
 }
 
 
 #[no_mangle]
-pub extern fn __rust_deallocate(ptr: *mut u8, _old_size: usize, _align: usize) {
-
-    HEAP.deallocate(ptr, _old_size)
+pub extern fn __rust_deallocate(ptr: *mut u8, _old_size: usize, align: usize) {
+    if track::enabled() {
+        track::forget(ptr as usize);
+    }
+    HEAP.deallocate(ptr, _old_size, align)
 }
 
 /*
@@ -23,21 +33,30 @@ pub extern fn malloc (size: usize) -> *mut u8 {
 }*/
 
 #[no_mangle]
-pub extern fn __rust_reallocate(ptr: *mut u8, _old_size: usize, size: usize,
-                                _align: usize) -> *mut u8 {
-    let new_addr = __rust_allocate(size, _align);
-    if new_addr.is_null() {
-        return null_mut();
+pub extern fn __rust_reallocate(ptr: *mut u8, old_size: usize, size: usize,
+                                align: usize) -> *mut u8 {
+    let ret = match HEAP.try_reallocate(ptr, old_size, size, align) {
+        Ok(ret) => ret,
+        // the old block is still there, as the caller expects
+        Err(_) => return null_mut(),
+    };
+    if track::enabled() {
+        track::moved(ptr as usize, ret, size);
     }
-    unsafe {memmove(new_addr, ptr, _old_size);}
-    new_addr
-
+    unsafe {transmute(ret)}
 }
 
 #[no_mangle]
-pub extern fn __rust_reallocate_inplace(_ptr: *mut u8, old_size: usize,
-                                        _size: usize, _align: usize) -> usize {
-    old_size // this api is not supported by libc
+pub extern fn __rust_reallocate_inplace(ptr: *mut u8, old_size: usize,
+                                        size: usize, align: usize) -> usize {
+    if HEAP.reallocate_inplace(ptr, old_size, size, align) {
+        if track::enabled() {
+            track::moved(ptr as usize, ptr as usize, size);
+        }
+        size
+    } else {
+        old_size
+    }
 }
 
 #[no_mangle]
@@ -10,6 +10,20 @@ impl<'a> Bitmap<'a> {
         unsafe { Bitmap(slice::from_raw_parts_mut(addr as *mut _, cap / 8)) }
     }
 
+    ///
+    /// sets every bit, only safe before the bitmap is shared
+    ///
+    pub fn fill(&mut self, val: bool) {
+        let byte = if val { !0u8 } else { 0u8 };
+        for b in self.0.iter_mut() {
+            *b = byte;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len() * 8
+    }
+
     pub fn get(&self, bit_index: usize) -> bool {
         let byte = self.get_byte(Bitmap::cal_byte_index(bit_index));
         let off = Bitmap::cal_bit_offset(bit_index);
@@ -34,9 +48,15 @@ impl<'a> Bitmap<'a> {
         }
     }
 
-    pub fn set_first_unused(&self) -> usize {
+    ///
+    /// sets the lowest clear bit and returns it, None if every bit is set
+    ///
+    pub fn set_first_unused(&self) -> Option<usize> {
         let mut i: usize = 0;
         loop {
+            if i >= self.0.len() {
+                return None;
+            }
             let byte = self.0[i];
             if byte != !0u8 {
                 let pos = (!byte).trailing_zeros();
@@ -47,7 +67,7 @@ impl<'a> Bitmap<'a> {
                 if !success {
                     continue;
                 } else {
-                    return i * 8 + pos as usize;
+                    return Some(i * 8 + pos as usize);
                 }
             }
             i += 1;
@@ -78,7 +98,7 @@ impl<'a> Bitmap<'a> {
 }
 
 pub fn test_bitmap() {
-    let mut b = Bitmap::new(0x600000, 0x1000000);
+    let mut b = Bitmap::new(::mem::paging::phys_to_virt(0x600000), 0x1000000);
     for i in 0..1000 {
         b.set(i * 9, true);
     }
@@ -93,7 +113,14 @@ pub fn test_bitmap() {
     }
 
     for _  in 0..100 {
-        b.set_first_unused();
+        b.set_first_unused().unwrap();
     }
+
+    // a full bitmap has to say so instead of running off its end
+    let mut small = Bitmap::new(::mem::paging::phys_to_virt(0x600000), 64);
+    small.fill(true);
+    small.set(13, false);
+    assert_eq!(small.set_first_unused(), Some(13));
+    assert_eq!(small.set_first_unused(), None);
     kprint!("bitmap test successful\n");
 }
\ No newline at end of file
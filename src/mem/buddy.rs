@@ -0,0 +1,282 @@
+use super::bitmap::Bitmap;
+use super::paging::{phys_to_virt, PHYS_MAP_BASE};
+use core::cell::UnsafeCell;
+use core::ptr;
+use interrupt::guard::IrqMutex;
+
+/// largest block is 2^18 frames (1 GiB)
+pub const MAX_ORDER: usize = 18;
+
+const FREE_MAGIC: usize = 0xf4eeb10c;
+
+/// devices with 32 bit addressing can only reach frames below this
+pub const DMA32_LIMIT: usize = 0x1_0000_0000;
+
+const ZONE_CNT: usize = 2;
+
+///
+/// Physical memory is split at DMA32_LIMIT. Blocks are aligned to their
+/// size and at most 1 GiB, so none of them straddles the boundary.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Zone {
+    /// below 4 GiB, for devices that only take 32 bit addresses
+    Dma32,
+    /// anything, allocations from here fall back to Dma32
+    Normal,
+}
+
+impl Zone {
+    pub fn of(frame: usize) -> Zone {
+        if frame * 4096 < DMA32_LIMIT { Zone::Dma32 } else { Zone::Normal }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Zone::Dma32 => 0,
+            Zone::Normal => 1,
+        }
+    }
+}
+
+///
+/// Lives in the first frame of every free block.
+/// Only the head of a free block carries the magic.
+///
+struct FreeBlock {
+    magic: usize,
+    order: usize,
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+struct FreeLists {
+    heads: [[*mut FreeBlock; MAX_ORDER + 1]; ZONE_CNT],
+}
+
+unsafe impl Send for FreeLists {}
+
+///
+/// Binary buddy allocator over physical frames.
+/// `freemap` has one bit per frame and is set for every frame that is
+/// allocated or reserved, so a buddy is free exactly when its first bit
+/// is clear. Every zone has its own free lists.
+///
+pub struct BuddyAllocator<'a> {
+    lists: IrqMutex<FreeLists>,
+    freemap: UnsafeCell<Bitmap<'a>>,
+    frame_cnt: usize,
+}
+
+unsafe impl<'a> Sync for BuddyAllocator<'a> {}
+
+impl<'a> BuddyAllocator<'a> {
+    ///
+    /// every clear bit in `freemap` is put on the free lists
+    ///
+    pub fn new(freemap: Bitmap<'a>, frame_cnt: usize) -> BuddyAllocator<'a> {
+        let ret = BuddyAllocator {
+            lists: IrqMutex::new(FreeLists { heads: [[ptr::null_mut(); MAX_ORDER + 1]; ZONE_CNT] }),
+            freemap: UnsafeCell::new(freemap),
+            frame_cnt: frame_cnt,
+        };
+
+        {
+            let mut lists = ret.lists.lock();
+            let mut frame = 0;
+            while frame < frame_cnt {
+                if ret.bitmap().get(frame) {
+                    frame += 1;
+                    continue;
+                }
+                let mut run = 0;
+                while frame + run < frame_cnt && !ret.bitmap().get(frame + run) {
+                    run += 1;
+                }
+                // carve the run into the largest naturally aligned blocks
+                let end = frame + run;
+                while frame < end {
+                    let mut order = 0;
+                    while order < MAX_ORDER
+                        && frame % (1 << (order + 1)) == 0
+                        && frame + (1 << (order + 1)) <= end {
+                        order += 1;
+                    }
+                    ret.push(&mut lists, frame, order);
+                    frame += 1 << order;
+                }
+            }
+        }
+        ret
+    }
+
+    ///
+    /// returns the first frame number of a free block of 2^order frames,
+    /// aligned to its size
+    ///
+    pub fn alloc(&self, order: usize) -> Option<usize> {
+        self.alloc_zone(order, Zone::Normal)
+    }
+
+    ///
+    /// like alloc, but the block comes from `zone`
+    ///
+    pub fn alloc_zone(&self, order: usize, zone: Zone) -> Option<usize> {
+        assert!(order <= MAX_ORDER);
+        let mut lists = self.lists.lock();
+        self.alloc_locked(&mut lists, order, zone)
+    }
+
+    pub fn free(&self, frame: usize, order: usize) {
+        assert!(order <= MAX_ORDER);
+        let mut lists = self.lists.lock();
+        self.free_locked(&mut lists, frame, order);
+    }
+
+    ///
+    /// fills `out` with single frames under one lock acquisition,
+    /// returns how many were allocated
+    ///
+    pub fn alloc_batch(&self, out: &mut [usize]) -> usize {
+        let mut lists = self.lists.lock();
+        for i in 0..out.len() {
+            match self.alloc_locked(&mut lists, 0, Zone::Normal) {
+                Some(f) => out[i] = f,
+                None => return i,
+            }
+        }
+        out.len()
+    }
+
+    pub fn free_batch(&self, frames: &[usize]) {
+        let mut lists = self.lists.lock();
+        for f in frames {
+            self.free_locked(&mut lists, *f, 0);
+        }
+    }
+
+    fn alloc_locked(&self, lists: &mut FreeLists, order: usize, zone: Zone) -> Option<usize> {
+        // normal allocations keep the low memory for devices as long as they can
+        let mut found = self.find(lists, order, zone);
+        if found.is_none() && zone == Zone::Normal {
+            found = self.find(lists, order, Zone::Dma32);
+        }
+        let (z, mut k) = match found {
+            Some(f) => f,
+            None => return None,
+        };
+
+        let frame = self.pop(lists, z, k);
+        // split, keeping the lower half
+        while k > order {
+            k -= 1;
+            self.push(lists, frame + (1 << k), k);
+        }
+        for f in frame..frame + (1 << order) {
+            self.bitmap().set(f, true);
+        }
+        Some(frame)
+    }
+
+    ///
+    /// zone and order of the smallest free block in `zone` that holds 2^order frames
+    ///
+    fn find(&self, lists: &FreeLists, order: usize, zone: Zone) -> Option<(Zone, usize)> {
+        let heads = &lists.heads[zone.index()];
+        let mut k = order;
+        while k <= MAX_ORDER && heads[k].is_null() {
+            k += 1;
+        }
+        if k > MAX_ORDER { None } else { Some((zone, k)) }
+    }
+
+    fn free_locked(&self, lists: &mut FreeLists, frame: usize, order: usize) {
+        assert!(frame % (1 << order) == 0, "misaligned free of frame 0x{:x}", frame);
+        for f in frame..frame + (1 << order) {
+            assert!(self.bitmap().get(f), "double free of frame 0x{:x}", f);
+            self.bitmap().set(f, false);
+        }
+
+        let mut frame = frame;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if buddy + (1 << order) > self.frame_cnt || !self.is_free_head(buddy, order) {
+                break;
+            }
+            self.remove(lists, buddy);
+            frame = if buddy < frame { buddy } else { frame };
+            order += 1;
+        }
+        self.push(lists, frame, order);
+    }
+
+    pub fn is_allocated(&self, frame: usize) -> bool {
+        self.bitmap().get(frame)
+    }
+
+    pub fn frame_cnt(&self) -> usize {
+        self.frame_cnt
+    }
+
+    fn bitmap(&self) -> &mut Bitmap<'a> {
+        unsafe { &mut *self.freemap.get() }
+    }
+
+    fn is_free_head(&self, frame: usize, order: usize) -> bool {
+        if self.bitmap().get(frame) {
+            return false;
+        }
+        let block = unsafe { &*block_at(frame) };
+        block.magic == FREE_MAGIC && block.order == order
+    }
+
+    fn push(&self, lists: &mut FreeLists, frame: usize, order: usize) {
+        let heads = &mut lists.heads[Zone::of(frame).index()];
+        let block = block_at(frame);
+        unsafe {
+            (*block).magic = FREE_MAGIC;
+            (*block).order = order;
+            (*block).prev = ptr::null_mut();
+            (*block).next = heads[order];
+            if let Some(next) = heads[order].as_mut() {
+                next.prev = block;
+            }
+        }
+        heads[order] = block;
+    }
+
+    fn pop(&self, lists: &mut FreeLists, zone: Zone, order: usize) -> usize {
+        let block = lists.heads[zone.index()][order];
+        assert!(!block.is_null());
+        let frame = frame_of(block);
+        self.remove(lists, frame);
+        frame
+    }
+
+    fn remove(&self, lists: &mut FreeLists, frame: usize) {
+        let block = block_at(frame);
+        unsafe {
+            assert!((*block).magic == FREE_MAGIC);
+            let order = (*block).order;
+            if let Some(prev) = (*block).prev.as_mut() {
+                prev.next = (*block).next;
+            } else {
+                lists.heads[Zone::of(frame).index()][order] = (*block).next;
+            }
+            if let Some(next) = (*block).next.as_mut() {
+                next.prev = (*block).prev;
+            }
+            (*block).magic = 0;
+        }
+    }
+}
+
+// free blocks are written through the direct map
+fn block_at(frame: usize) -> *mut FreeBlock {
+    phys_to_virt(frame * 4096) as *mut FreeBlock
+}
+
+fn frame_of(block: *mut FreeBlock) -> usize {
+    (block as usize - PHYS_MAP_BASE) / 4096
+}
@@ -0,0 +1,175 @@
+use interrupt::guard::IrqMutex;
+use core::mem::size_of;
+use core::slice;
+use rlibc::memset;
+use super::track::CALLER_DEPTH;
+
+// guard bytes on both sides of every payload
+const REDZONE: usize = 16;
+const REDZONE_BYTE: u8 = 0xfd;
+// fresh payloads, so reads of uninitialized memory stand out
+const ALLOC_BYTE: u8 = 0xcd;
+// freed payloads while they sit in the quarantine
+const POISON_BYTE: u8 = 0x6b;
+
+const LIVE_MAGIC: usize = 0x11fe11fe;
+const FREED_MAGIC: usize = 0xdeadf4ee;
+
+// freed blocks are held back this long before the heap gets them
+const QUARANTINE_SIZE: usize = 64;
+
+const MIN_ALIGN: usize = 16;
+
+///
+/// Sits right below the front red zone.
+///
+struct DebugHeader {
+    state: usize,
+    len: usize,
+    callers: [usize; CALLER_DEPTH],
+}
+
+#[derive(Copy, Clone)]
+struct Quarantined {
+    payload: usize,
+    len: usize,
+    align: usize,
+}
+
+struct Quarantine {
+    entries: [Quarantined; QUARANTINE_SIZE],
+    // oldest entry
+    head: usize,
+    cnt: usize,
+}
+
+static QUARANTINE: IrqMutex<Quarantine> = IrqMutex::new(Quarantine {
+    entries: [Quarantined { payload: 0, len: 0, align: 0 }; QUARANTINE_SIZE],
+    head: 0,
+    cnt: 0,
+});
+
+fn align_of(align: usize) -> usize {
+    if align < MIN_ALIGN { MIN_ALIGN } else { align }
+}
+
+///
+/// bytes between the start of the raw block and the payload
+///
+fn prefix(align: usize) -> usize {
+    let align = align_of(align);
+    let need = size_of::<DebugHeader>() + REDZONE;
+    ((need - 1) / align + 1) * align
+}
+
+///
+/// what has to be asked from the heap for a payload of `len` bytes
+///
+pub fn padded(len: usize, align: usize) -> usize {
+    prefix(align) + len + REDZONE
+}
+
+///
+/// Sets up a raw block of `padded(len, align)` bytes and returns the
+/// payload. Every call first checks the quarantine for writes to freed
+/// memory.
+///
+pub fn arm(raw: usize, len: usize, align: usize, callers: [usize; CALLER_DEPTH]) -> usize {
+    check_quarantine();
+    let payload = raw + prefix(align);
+    let hdr = header(payload);
+    hdr.state = LIVE_MAGIC;
+    hdr.len = len;
+    hdr.callers = callers;
+    unsafe {
+        memset((payload - REDZONE) as *mut u8, REDZONE_BYTE as i32, REDZONE);
+        memset(payload as *mut u8, ALLOC_BYTE as i32, len);
+        memset((payload + len) as *mut u8, REDZONE_BYTE as i32, REDZONE);
+    }
+    payload
+}
+
+///
+/// Checks and poisons a block that is being freed and puts it into the
+/// quarantine. Returns the raw block, its padded length and alignment of
+/// whatever fell out of the quarantine, which the caller gives back to
+/// the heap.
+///
+pub fn release(payload: usize, len: usize, align: usize) -> Option<(usize, usize, usize)> {
+    {
+        let hdr = header(payload);
+        match hdr.state {
+            LIVE_MAGIC => {},
+            FREED_MAGIC => report("double free", payload, hdr),
+            _ => report("free of a corrupted or foreign block", payload, hdr),
+        }
+        if hdr.len != len {
+            report("free with the wrong size", payload, hdr);
+        }
+        if !filled(payload - REDZONE, REDZONE, REDZONE_BYTE) {
+            report("buffer underflow", payload, hdr);
+        }
+        if !filled(payload + len, REDZONE, REDZONE_BYTE) {
+            report("buffer overflow", payload, hdr);
+        }
+        hdr.state = FREED_MAGIC;
+        unsafe { memset(payload as *mut u8, POISON_BYTE as i32, len); }
+    }
+
+    let mut q = QUARANTINE.lock();
+    let entry = Quarantined { payload: payload, len: len, align: align };
+    if q.cnt < QUARANTINE_SIZE {
+        let idx = (q.head + q.cnt) % QUARANTINE_SIZE;
+        q.entries[idx] = entry;
+        q.cnt += 1;
+        return None;
+    }
+    let head = q.head;
+    let old = q.entries[head];
+    q.entries[head] = entry;
+    q.head = (head + 1) % QUARANTINE_SIZE;
+    drop(q);
+
+    verify_poison(&old);
+    Some((old.payload - prefix(old.align), padded(old.len, old.align), old.align))
+}
+
+fn check_quarantine() {
+    let q = QUARANTINE.lock();
+    for i in 0..q.cnt {
+        let entry = q.entries[(q.head + i) % QUARANTINE_SIZE];
+        verify_poison(&entry);
+    }
+}
+
+fn verify_poison(entry: &Quarantined) {
+    let hdr = header(entry.payload);
+    if hdr.state != FREED_MAGIC {
+        report("header of a freed block overwritten", entry.payload, hdr);
+    }
+    if !filled(entry.payload, entry.len, POISON_BYTE) {
+        report("write after free", entry.payload, hdr);
+    }
+}
+
+fn header<'a>(payload: usize) -> &'a mut DebugHeader {
+    unsafe { &mut *((payload - REDZONE - size_of::<DebugHeader>()) as *mut DebugHeader) }
+}
+
+fn filled(addr: usize, len: usize, val: u8) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+    bytes.iter().all(|b| *b == val)
+}
+
+fn report(what: &str, payload: usize, hdr: &DebugHeader) -> ! {
+    sprint!("debug heap: {} at 0x{:x}, {} bytes, allocated from", what, payload, hdr.len);
+    for c in hdr.callers.iter() {
+        if *c == 0 {
+            break;
+        }
+        sprint!(" 0x{:x}", c);
+    }
+    sprint!("\n");
+    panic!("debug heap: {} at 0x{:x} ({} bytes, allocated from 0x{:x})",
+           what, payload, hdr.len, hdr.callers[0]);
+}
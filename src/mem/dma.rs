@@ -0,0 +1,57 @@
+use super::FRAME;
+use super::frame::order_for;
+use super::paging::phys_to_virt;
+use super::oom::OutOfMemory;
+pub use super::buddy::Zone;
+
+///
+/// Physically contiguous memory a device can read and write.
+/// The CPU reaches it through the direct map, the device through
+/// `bus_addr`. Without an IOMMU the bus address is the physical one.
+///
+pub struct DmaBuffer {
+    paddr: usize,
+    len: usize,
+    order: usize,
+}
+
+impl DmaBuffer {
+    ///
+    /// `len` zeroed bytes from `zone`, aligned to a page at least
+    ///
+    pub fn new(len: usize, zone: Zone) -> Result<DmaBuffer, OutOfMemory> {
+        assert!(len > 0);
+        let order = order_for(len);
+        let paddr = FRAME.try_alloc_order_in(order, zone)?;
+        unsafe { ::rlibc::memset(phys_to_virt(paddr) as *mut u8, 0, 4096 << order) };
+        Ok(DmaBuffer {
+            paddr: paddr,
+            len: len,
+            order: order,
+        })
+    }
+
+    ///
+    /// where the CPU sees the buffer
+    ///
+    pub fn as_ptr(&self) -> *mut u8 {
+        phys_to_virt(self.paddr) as *mut u8
+    }
+
+    ///
+    /// the address to program into the device
+    ///
+    pub fn bus_addr(&self) -> usize {
+        self.paddr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        FRAME.dealloc_order(self.paddr, self.order);
+    }
+}
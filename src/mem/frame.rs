@@ -1,67 +1,632 @@
 use super::bitmap;
+use super::buddy::{BuddyAllocator, Zone, MAX_ORDER};
+use super::vrange::VirtualRangeAllocator;
+use super::memmap::{MemoryMap, MemoryRegion, RegionKind};
+use super::address_space::KERNEL_SPACE;
+use super::vma::{self, Vma, VmaKind};
+use super::oom::{self, OutOfMemory};
+use super::memtest;
 use core::cell::UnsafeCell;
 use core::sync::atomic::*;
 use super::paging::*;
 use x86::shared::tlb;
+use interrupt::guard::IrqMutex;
+use core::mem::size_of;
+use core::slice;
+use devices::apic;
 
 
-const KER_LOWER_BOUND: u64 = 0xc0000000;
+// kernel virtual memory gets the PML4 slot right below the kernel image
+const KER_LOWER_BOUND: usize = 0xffff_ff00_0000_0000;
 
-static FREE_ADDRESS: AtomicUsize = ATOMIC_USIZE_INIT;
+static KERNEL_VRANGE: VirtualRangeAllocator = VirtualRangeAllocator::new(KER_LOWER_BOUND);
+
+// leave the BIOS area and the AP trampoline at 0x1000 alone
+const LOW_MEMORY_LIMIT: usize = 0x100000;
+
+// per-cpu cache of single frames, refilled and drained in batches
+const MAGAZINE_SIZE: usize = 32;
+const MAGAZINE_BATCH: usize = 16;
+
+// pages unmapped per TLB shootdown in dealloc_multiple and Vma::depopulate
+pub const UNMAP_BATCH: usize = 32;
+
+// frames held back for atomic allocations, see oom::atomic
+const EMERGENCY_FRAMES: usize = 64;
+
+struct Magazine {
+    cnt: usize,
+    frames: [usize; MAGAZINE_SIZE],
+}
+
+///
+/// Single frames kept back for atomic allocations. They are not counted
+/// as free, are only handed out once the buddy allocator is empty and
+/// the next frames that get freed refill the pool.
+///
+struct EmergencyPool {
+    cnt: usize,
+    frames: [usize; EMERGENCY_FRAMES],
+}
 
 unsafe impl<'a> Sync for FrameAllocator<'a> {}
 
 pub struct FrameAllocator<'a> {
-    available_base: usize,
-    upper: usize,
-    freemap: UnsafeCell<bitmap::Bitmap<'a>>,
+    map: MemoryMap,
+    frame_cnt: usize,
+    total_frames: usize,
+    free_frames: AtomicUsize,
+    buddy: BuddyAllocator<'a>,
+    magazines: &'a [IrqMutex<Magazine>],
+    emergency: IrqMutex<EmergencyPool>,
+    // set while the emergency pool is not full, dealloc only locks it then
+    emergency_low: AtomicBool,
+    // references beyond the first one, per frame
+    refs: &'a [AtomicU16],
+    zero_page: usize,
 }
 
 impl<'a> FrameAllocator<'a> {
-    pub fn new(base: usize, len_bytes: usize) -> FrameAllocator<'a> {
-        FREE_ADDRESS.store(KER_LOWER_BOUND as usize, Ordering::SeqCst);
+    ///
+    /// `kernel_end` is the first physical address not used by the kernel
+    /// image or the multiboot information. With `memtest` the free
+    /// frames are tested first and the bad ones left out.
+    ///
+    pub fn new(map: MemoryMap, kernel_end: usize, memtest: bool) -> FrameAllocator<'a> {
+        let upper = map.usable_end();
+        // one bit per frame, whole bytes
+        let frame_cnt = ((upper / 4096 + 7) / 8) * 8;
+        let bitmap_bytes = page_align(frame_cnt / 8);
+        // the page tables for the rest of the direct map go right after the bitmap
+        let boot_bytes = bitmap_bytes + direct_map_tables(upper) * 4096;
+        let low = page_align(if kernel_end > LOW_MEMORY_LIMIT { kernel_end } else { LOW_MEMORY_LIMIT });
+
+        // at the start of the first usable region that can hold both,
+        // it has to be reachable through the boot direct map
+        let mut bitmap_base = 0;
+        for r in map.regions() {
+            if r.kind != RegionKind::Usable {
+                continue;
+            }
+            let start = if page_align(r.base) > low { page_align(r.base) } else { low };
+            if start + boot_bytes <= r.end() && start + boot_bytes <= BOOT_DIRECT_MAP {
+                bitmap_base = start;
+                break;
+            }
+        }
+        assert!(bitmap_base != 0, "no room for the frame bitmap");
+
+        let tables = map_physical_memory(upper, bitmap_base + bitmap_bytes);
+        let boot_end = bitmap_base + bitmap_bytes + tables * 4096;
+
+        let mut freemap = bitmap::Bitmap::new(phys_to_virt(bitmap_base), frame_cnt);
+        freemap.fill(true);
+
+        let mut free = 0;
+        let mut usable = 0;
+        for r in map.regions() {
+            if r.kind != RegionKind::Usable {
+                continue;
+            }
+            usable += r.len / 4096;
+            let first = page_align(r.base) / 4096;
+            let last = r.end() / 4096;
+            for frame in first..last {
+                let addr = frame * 4096;
+                if addr < low || addr >= upper {
+                    continue;
+                }
+                if addr >= bitmap_base && addr < boot_end {
+                    continue;
+                }
+                freemap.set(frame, false);
+                free += 1;
+            }
+        }
+        if memtest {
+            free -= memtest::run(&mut freemap, frame_cnt);
+        }
+        // what the firmware reports minus low memory, boot tables and bad frames
+        let total = free;
+        kprint!("frame allocator: {} frames usable, {} managed\n", usable, total);
+
+        let buddy = BuddyAllocator::new(freemap, frame_cnt);
+
+        // the magazines are too big for the stack, take them from the buddy allocator
+        let mag_bytes = size_of::<IrqMutex<Magazine>>() * apic::MAX_CPU;
+        let mag_order = order_for(mag_bytes);
+        let mag_addr = buddy.alloc(mag_order).expect("no memory for frame magazines") * 4096;
+        let magazines: &'a [IrqMutex<Magazine>] = unsafe {
+            // an all zero IrqMutex<Magazine> is an unlocked, empty magazine
+            ::rlibc::memset(phys_to_virt(mag_addr) as *mut u8, 0, mag_bytes);
+            slice::from_raw_parts(phys_to_virt(mag_addr) as *const _, apic::MAX_CPU)
+        };
+        free -= 1 << mag_order;
+
+        let refs_bytes = size_of::<AtomicU16>() * frame_cnt;
+        let refs_order = order_for(refs_bytes);
+        let refs_addr = buddy.alloc(refs_order).expect("no memory for frame reference counts") * 4096;
+        let refs: &'a [AtomicU16] = unsafe {
+            ::rlibc::memset(phys_to_virt(refs_addr) as *mut u8, 0, refs_bytes);
+            slice::from_raw_parts(phys_to_virt(refs_addr) as *const _, frame_cnt)
+        };
+        free -= 1 << refs_order;
+
+        let zero_page = buddy.alloc(0).expect("no memory for the zero page") * 4096;
+        unsafe { ::rlibc::memset(phys_to_virt(zero_page) as *mut u8, 0, 4096) };
+        free -= 1;
+
+        let mut emergency = EmergencyPool { cnt: 0, frames: [0; EMERGENCY_FRAMES] };
+        emergency.cnt = buddy.alloc_batch(&mut emergency.frames);
+        free -= emergency.cnt;
+
+        oom::register("frame magazines", drain_magazines);
+
         FrameAllocator {
-            freemap: UnsafeCell::new(bitmap::Bitmap::new(base, len_bytes / 4096)),
-            available_base: ((base + (len_bytes / (4096 * 8))) / 4096 + 1) * 4096,
-            upper: base + len_bytes,
+            map: map,
+            frame_cnt: frame_cnt,
+            total_frames: total,
+            free_frames: AtomicUsize::new(free),
+            buddy: buddy,
+            magazines: magazines,
+            emergency_low: AtomicBool::new(emergency.cnt < EMERGENCY_FRAMES),
+            emergency: IrqMutex::new(emergency),
+            refs: refs,
+            zero_page: zero_page,
         }
     }
 
+    ///
+    /// number of frames the allocator manages, allocated or not
+    ///
+    pub fn total(&self) -> usize {
+        self.total_frames
+    }
+
+    ///
+    /// number of frames that can still be allocated
+    ///
+    pub fn free(&self) -> usize {
+        self.free_frames.load(Ordering::Relaxed)
+    }
+
+    ///
+    /// a frame of zeros that is never freed, it may only be mapped
+    /// read-only and is not reference counted
+    ///
+    pub fn zero_page(&self) -> usize {
+        self.zero_page
+    }
+
+    ///
+    /// adds a reference to an allocated frame, e.g. for a second mapping
+    ///
+    pub fn get_ref(&self, paddr: usize) {
+        if paddr == self.zero_page {
+            return;
+        }
+        let old = self.refs[paddr / 4096].fetch_add(1, Ordering::SeqCst);
+        assert!(old != ::core::u16::MAX, "too many references to frame 0x{:x}", paddr);
+    }
+
+    ///
+    /// drops a reference, the last one frees the frame.
+    /// Returns whether it was freed.
+    ///
+    pub fn put_ref(&self, paddr: usize) -> bool {
+        if paddr == self.zero_page {
+            return false;
+        }
+        let refs = &self.refs[paddr / 4096];
+        loop {
+            let old = refs.load(Ordering::SeqCst);
+            if old == 0 {
+                self.dealloc(paddr);
+                return true;
+            }
+            if refs.compare_and_swap(old, old - 1, Ordering::SeqCst) == old {
+                return false;
+            }
+        }
+    }
+
+    ///
+    /// number of owners of an allocated frame
+    ///
+    pub fn ref_count(&self, paddr: usize) -> usize {
+        self.refs[paddr / 4096].load(Ordering::SeqCst) as usize + 1
+    }
+
+    pub fn region_of(&self, paddr: usize) -> Option<MemoryRegion> {
+        self.map.region_of(paddr)
+    }
 
     pub fn alloc_multiple(&self, cnt: usize) -> usize {
-        let cur = FREE_ADDRESS.fetch_add(cnt * 4096, Ordering::SeqCst);
+        self.alloc_multiple_aligned(cnt, 4096, 0)
+    }
+
+    pub fn try_alloc_multiple(&self, cnt: usize) -> Result<usize, OutOfMemory> {
+        self.try_alloc_multiple_aligned(cnt, 4096, 0)
+    }
+
+    ///
+    /// like alloc_multiple, but `offset` bytes into the returned range
+    /// is a multiple of `align`
+    ///
+    pub fn alloc_multiple_aligned(&self, cnt: usize, align: usize, offset: usize) -> usize {
+        match self.try_alloc_multiple_aligned(cnt, align, offset) {
+            Ok(vaddr) => vaddr,
+            Err(_) => panic!("OOM! {} pages", cnt),
+        }
+    }
+
+    pub fn try_alloc_multiple_aligned(&self, cnt: usize, align: usize, offset: usize) -> Result<usize, OutOfMemory> {
+        // fail early rather than after taking every frame there is,
+        // the counter moves under us so it is only read once
+        let free = self.free();
+        if cnt > free && cnt > free + oom::shrink(cnt.saturating_sub(free)) {
+            return Err(OutOfMemory);
+        }
+        let cur = KERNEL_VRANGE.alloc_aligned(cnt * 4096, align, offset);
+        if let Err(e) = self.map_fresh(cur, cnt) {
+            KERNEL_VRANGE.free(cur, cnt * 4096);
+            return Err(e);
+        }
+        Ok(cur)
+    }
+
+    ///
+    /// maps `cnt` more pages starting at `vaddr`,
+    /// fails if that virtual range is taken or there is no memory
+    ///
+    pub fn extend_multiple(&self, vaddr: usize, cnt: usize) -> bool {
+        if !KERNEL_VRANGE.claim(vaddr, cnt * 4096) {
+            return false;
+        }
+        if self.map_fresh(vaddr, cnt).is_err() {
+            KERNEL_VRANGE.free(vaddr, cnt * 4096);
+            return false;
+        }
+        true
+    }
+
+    ///
+    /// backs `cnt` pages at `vaddr` with new frames, all or nothing
+    ///
+    fn map_fresh(&self, vaddr: usize, cnt: usize) -> Result<(), OutOfMemory> {
+        for i in 0..cnt {
+            match self.try_alloc() {
+                Ok(pframe) => { page_map(vaddr + i * 4096, pframe); },
+                Err(e) => {
+                    self.unmap_fresh(vaddr, i);
+                    return Err(e);
+                },
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// undoes map_fresh for the first `cnt` pages, the range stays allocated
+    ///
+    fn unmap_fresh(&self, vaddr: usize, cnt: usize) {
+        for i in 0..cnt {
+            if let Some(pframe) = KERNEL_SPACE.unmap(vaddr + i * 4096) {
+                self.dealloc(pframe);
+            }
+        }
+    }
+
+    ///
+    /// moves the frames behind `cnt` pages at `vaddr` to a new range of
+    /// `new_cnt` pages without copying them, the extra pages get fresh
+    /// frames. `align` and `offset` are as in alloc_multiple_aligned.
+    ///
+    pub fn remap_multiple(&self, vaddr: usize, cnt: usize, new_cnt: usize,
+                          align: usize, offset: usize) -> Result<usize, OutOfMemory> {
+        assert!(new_cnt >= cnt);
+        let new = KERNEL_VRANGE.alloc_aligned(new_cnt * 4096, align, offset);
+        // the extra pages first, nothing has moved yet if that fails
+        if let Err(e) = self.map_fresh(new + cnt * 4096, new_cnt - cnt) {
+            KERNEL_VRANGE.free(new, new_cnt * 4096);
+            return Err(e);
+        }
         for i in 0..cnt {
-            let pframe = self.alloc();
-            page_map(cur + i * 4096, pframe);
+            let page = vaddr + i * 4096;
+            if let Some(pframe) = KERNEL_SPACE.unmap_deferred(page) {
+                page_map(new + i * 4096, pframe);
+            }
+        }
+        // the frames stay in use, one flush for the old range is enough
+        KERNEL_SPACE.shootdown(vaddr, cnt);
+        KERNEL_VRANGE.free(vaddr, cnt * 4096);
+        Ok(new)
+    }
+
+    ///
+    /// unmaps `cnt` pages starting at `vaddr`, gives the frames back
+    /// and recycles the virtual range. Pages that are not mapped
+    /// (e.g. stack guards) are skipped.
+    ///
+    pub fn dealloc_multiple(&self, vaddr: usize, cnt: usize) {
+        // frames are only freed once no cpu can reach them any more
+        let mut frames = [0usize; UNMAP_BATCH];
+        let mut done = 0;
+        while done < cnt {
+            let chunk = if cnt - done < UNMAP_BATCH { cnt - done } else { UNMAP_BATCH };
+            let start = vaddr + done * 4096;
+            let mut n = 0;
+            for i in 0..chunk {
+                if let Some(pframe) = KERNEL_SPACE.unmap_deferred(start + i * 4096) {
+                    frames[n] = pframe;
+                    n += 1;
+                }
+            }
+            KERNEL_SPACE.shootdown(start, chunk);
+            for f in &frames[..n] {
+                self.dealloc(*f);
+            }
+            done += chunk;
+        }
+        KERNEL_VRANGE.free(vaddr, cnt * 4096);
+    }
+
+    ///
+    /// maps `cnt` pages of physical memory at `paddr` that the allocator
+    /// does not hand out, e.g. device memory
+    ///
+    pub fn map_physical(&self, paddr: usize, cnt: usize, cache: CacheMode) -> usize {
+        let cur = KERNEL_VRANGE.alloc(cnt * 4096);
+        for i in 0..cnt {
+            page_map_prot(cur + i * 4096, paddr + i * 4096, READ | WRITE, cache);
         }
         cur
     }
 
-    pub fn dealloc_multiple(&self, cnt: usize) {
+    ///
+    /// takes the value returned by `map_physical`, the memory itself is left alone
+    ///
+    pub fn unmap_physical(&self, vaddr: usize, cnt: usize) {
+        for i in 0..cnt {
+            KERNEL_SPACE.unmap_deferred(vaddr + i * 4096);
+        }
+        KERNEL_SPACE.shootdown(vaddr, cnt);
+        KERNEL_VRANGE.free(vaddr, cnt * 4096);
+    }
+
+    ///
+    /// reserves `cnt` pages of kernel address space that get zeroed
+    /// frames on first access. Nothing that holds a frame allocator
+    /// lock may touch them.
+    ///
+    pub fn reserve(&self, cnt: usize) -> usize {
+        let ret = KERNEL_VRANGE.alloc(cnt * 4096);
+        KERNEL_SPACE.add_vma(Vma::new(ret, ret + cnt * 4096, VmaKind::ZeroFill, vma::READ | vma::WRITE));
+        ret
+    }
 
+    ///
+    /// takes the value returned by `reserve`
+    ///
+    pub fn unreserve(&self, vaddr: usize, cnt: usize) {
+        KERNEL_SPACE.remove_vma(vaddr).expect("not a reserved range");
+        KERNEL_VRANGE.free(vaddr, cnt * 4096);
     }
 
     pub fn alloc_stack(&self, cnt_in_page: usize) -> usize {
-        let ret = self.alloc_multiple(cnt_in_page + 1);
-        page_unmap(ret);
-        ret + ((cnt_in_page + 1) * 4096)
+        match self.try_alloc_stack(cnt_in_page) {
+            Ok(top) => top,
+            Err(_) => panic!("OOM! stack of {} pages", cnt_in_page),
+        }
+    }
+
+    ///
+    /// returns the top of a stack of `cnt_in_page` pages,
+    /// with an unmapped guard page below it
+    ///
+    pub fn try_alloc_stack(&self, cnt_in_page: usize) -> Result<usize, OutOfMemory> {
+        let ret = KERNEL_VRANGE.alloc((cnt_in_page + 1) * 4096);
+        // the lowest page stays unmapped as the guard
+        if let Err(e) = self.map_fresh(ret + 4096, cnt_in_page) {
+            KERNEL_VRANGE.free(ret, (cnt_in_page + 1) * 4096);
+            return Err(e);
+        }
+        Ok(ret + ((cnt_in_page + 1) * 4096))
+    }
+
+    ///
+    /// takes the value returned by `alloc_stack`
+    ///
+    pub fn dealloc_stack(&self, top: usize, cnt_in_page: usize) {
+        self.dealloc_multiple(top - (cnt_in_page + 1) * 4096, cnt_in_page + 1);
     }
 
     pub fn alloc(&self) -> usize {
-        let fm: &mut bitmap::Bitmap = unsafe { &mut *self.freemap.get() };
-        let pos = fm.set_first_unused();
-        if self.available_base >= self.upper {
-            panic!("OOM!");
+        match self.try_alloc() {
+            Ok(frame) => frame,
+            Err(_) => panic!("OOM! no frame left"),
         }
-        let ret = self.available_base + 4096 * pos;
-        //kprint!("new frame = 0x{:x}\n", ret);
-        return ret;
+    }
+
+    ///
+    /// a single frame, the shrink hooks are run before it fails
+    ///
+    pub fn try_alloc(&self) -> Result<usize, OutOfMemory> {
+        if let Some(mag) = self.magazine() {
+            let mut guard = mag.lock();
+            let m: &mut Magazine = &mut *guard;
+            if m.cnt == 0 {
+                m.cnt = self.buddy.alloc_batch(&mut m.frames[..MAGAZINE_BATCH]);
+            }
+            if m.cnt > 0 {
+                m.cnt -= 1;
+                self.free_frames.fetch_sub(1, Ordering::Relaxed);
+                let ret = m.frames[m.cnt] * 4096;
+                //kprint!("new frame = 0x{:x}\n", ret);
+                return Ok(ret);
+            }
+        }
+        self.try_alloc_order(0)
     }
 
     pub fn dealloc(&self, addr: usize) {
-        let fm: &mut bitmap::Bitmap = unsafe { &mut *self.freemap.get() };
-        let pos = (addr - self.available_base) / 4096;
-        assert!(fm.get(pos));
-        fm.set(pos, false);
+        assert!(addr % 4096 == 0);
+        assert!(addr / 4096 < self.frame_cnt);
+        // cached frames stay marked allocated, so this only catches
+        // frames that already went back to the buddy allocator
+        assert!(self.buddy.is_allocated(addr / 4096), "double free of frame 0x{:x}", addr);
+        if self.emergency_low.load(Ordering::Relaxed) && self.refill_emergency(addr) {
+            return;
+        }
+        if let Some(mag) = self.magazine() {
+            let mut guard = mag.lock();
+            let m: &mut Magazine = &mut *guard;
+            assert!(!m.frames[..m.cnt].contains(&(addr / 4096)), "double free of frame 0x{:x}", addr);
+            if m.cnt == MAGAZINE_SIZE {
+                // give the older half back to the global pool
+                self.buddy.free_batch(&m.frames[..MAGAZINE_BATCH]);
+                for i in MAGAZINE_BATCH..MAGAZINE_SIZE {
+                    m.frames[i - MAGAZINE_BATCH] = m.frames[i];
+                }
+                m.cnt -= MAGAZINE_BATCH;
+            }
+            m.frames[m.cnt] = addr / 4096;
+            m.cnt += 1;
+            self.free_frames.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.dealloc_order(addr, 0);
+    }
+
+    ///
+    /// the last resort of atomic single frame allocations
+    ///
+    fn alloc_emergency(&self, order: usize, zone: Zone) -> Result<usize, OutOfMemory> {
+        if order != 0 || zone != Zone::Normal || !oom::atomic() {
+            return Err(OutOfMemory);
+        }
+        let mut guard = self.emergency.lock();
+        let pool: &mut EmergencyPool = &mut *guard;
+        if pool.cnt == 0 {
+            return Err(OutOfMemory);
+        }
+        pool.cnt -= 1;
+        self.emergency_low.store(true, Ordering::Relaxed);
+        Ok(pool.frames[pool.cnt] * 4096)
     }
+
+    ///
+    /// keeps a freed frame if the emergency pool is short of frames,
+    /// returns false if it is full
+    ///
+    fn refill_emergency(&self, addr: usize) -> bool {
+        let mut guard = self.emergency.lock();
+        let pool: &mut EmergencyPool = &mut *guard;
+        if pool.cnt == EMERGENCY_FRAMES {
+            return false;
+        }
+        pool.frames[pool.cnt] = addr / 4096;
+        pool.cnt += 1;
+        if pool.cnt == EMERGENCY_FRAMES {
+            self.emergency_low.store(false, Ordering::Relaxed);
+        }
+        true
+    }
+
+    ///
+    /// frames left in the emergency pool
+    ///
+    pub fn emergency_left(&self) -> usize {
+        self.emergency.lock().cnt
+    }
+
+    fn magazine(&self) -> Option<&IrqMutex<Magazine>> {
+        if !apic::local_apic_enabled() {
+            return None;
+        }
+        let id = apic::get_cpu_id() as usize;
+        if id < apic::MAX_CPU {
+            Some(&self.magazines[id])
+        } else {
+            None
+        }
+    }
+
+    ///
+    /// allocates 2^order physically contiguous frames, aligned to
+    /// their size. Returns the physical address of the first one.
+    ///
+    pub fn alloc_order(&self, order: usize) -> usize {
+        match self.try_alloc_order(order) {
+            Ok(frame) => frame,
+            Err(_) => panic!("OOM! order = {}", order),
+        }
+    }
+
+    pub fn try_alloc_order(&self, order: usize) -> Result<usize, OutOfMemory> {
+        self.try_alloc_order_in(order, Zone::Normal)
+    }
+
+    ///
+    /// like try_alloc_order, restricted to `zone`
+    ///
+    pub fn try_alloc_order_in(&self, order: usize, zone: Zone) -> Result<usize, OutOfMemory> {
+        assert!(order <= MAX_ORDER);
+        let frame = match self.buddy.alloc_zone(order, zone) {
+            Some(f) => f,
+            None => {
+                // ask the caches for memory and try once more
+                oom::shrink(1 << order);
+                match self.buddy.alloc_zone(order, zone) {
+                    Some(f) => f,
+                    None => return self.alloc_emergency(order, zone),
+                }
+            },
+        };
+        self.free_frames.fetch_sub(1 << order, Ordering::Relaxed);
+        Ok(frame * 4096)
+    }
+
+    ///
+    /// gives the frames cached by every cpu back to the buddy allocator,
+    /// magazines that are in use right now are skipped
+    ///
+    pub fn drain(&self) -> usize {
+        let mut ret = 0;
+        for mag in self.magazines.iter() {
+            if let Some(mut guard) = mag.try_lock() {
+                let m: &mut Magazine = &mut *guard;
+                self.buddy.free_batch(&m.frames[..m.cnt]);
+                ret += m.cnt;
+                m.cnt = 0;
+            }
+        }
+        ret
+    }
+
+    pub fn dealloc_order(&self, addr: usize, order: usize) {
+        assert!(addr % 4096 == 0);
+        assert!(addr / 4096 < self.frame_cnt);
+        self.buddy.free(addr / 4096, order);
+        self.free_frames.fetch_add(1 << order, Ordering::Relaxed);
+    }
+}
+
+fn drain_magazines(_frames: usize) -> usize {
+    super::FRAME.drain()
+}
+
+///
+/// smallest order whose block holds `bytes`
+///
+pub fn order_for(bytes: usize) -> usize {
+    let mut order = 0;
+    while (4096 << order) < bytes {
+        order += 1;
+    }
+    order
+}
+
+fn page_align(addr: usize) -> usize {
+    ((addr + 4095) / 4096) * 4096
 }
@@ -1,4 +1,4 @@
-use spin::*;
+use interrupt::guard::{InterruptGuard, IrqMutex, IrqMutexGuard};
 use core::sync::atomic::*;
 use core::intrinsics::transmute;
 use core::mem::transmute_copy;
@@ -7,18 +7,38 @@ use core::mem::size_of;
 use core::iter::*;
 use core::marker::PhantomData;
 use devices::serial;
+use rlibc::memmove;
 use super::FRAME;
+use super::slab;
+use super::slab::SlabAllocator;
+use super::oom::{self, OutOfMemory};
+#[cfg(feature = "debug_heap")]
+use super::debug_heap;
+#[cfg(feature = "debug_heap")]
+use super::track;
 
 lazy_static! {
-    pub static ref HEAP: HeapAllocator =
+    pub static ref HEAP: HeapAllocator = {
+        oom::register("slab caches", shrink_heap);
         HeapAllocator {
             arenas: AtomicPtr::new(ptr::null_mut()),
-        };
+            slabs: SlabAllocator::new(),
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            huge_pages: AtomicUsize::new(0),
+        }
+    };
+}
+
+fn shrink_heap(_frames: usize) -> usize {
+    HEAP.shrink()
 }
 
 macro_rules! pointer_sanity {
     ($ptr:expr) => {
-        unsafe {assert!(transmute_copy::<_, usize>(&$ptr) < 0xFFFFFFFF);}
+        // heap memory is always in the upper half
+        unsafe {assert!(transmute_copy::<_, usize>(&$ptr) >= ::mem::paging::PHYS_MAP_BASE);}
     };
 }
 
@@ -28,13 +48,76 @@ macro_rules! aligned_size {
     };
 }
 
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+///
+/// Small objects come from the slab allocator, whatever is too big
+/// for a slab but fits in a page goes to the first-fit arenas, the
+/// rest gets pages of its own.
+///
+/// Every allocator lock keeps interrupts off while it is held, so
+/// interrupt handlers and the scheduler may allocate and free. What is
+/// allocated with interrupts off is atomic: the shrink hooks are not
+/// run and single frames can come from the emergency pool of the frame
+/// allocator, see `oom::atomic`. `try_allocate_atomic` makes such an
+/// allocation from anywhere.
+///
 pub struct HeapAllocator {
-    arenas: AtomicPtr<Arena>
+    arenas: AtomicPtr<Arena>,
+    slabs: SlabAllocator,
+    // bytes as requested by the callers
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+    live: AtomicUsize,
+    huge_pages: AtomicUsize,
+}
+
+///
+/// Snapshot of the heap and the frames behind it, see `HEAP.stats()`.
+/// The counters are read one by one, so they are only roughly consistent.
+///
+#[derive(Copy, Clone, Debug)]
+pub struct HeapStats {
+    pub bytes_in_use: usize,
+    pub peak_bytes: usize,
+    pub live_allocations: usize,
+    pub arenas: usize,
+    pub arena_free_bytes: usize,
+    pub arena_largest_free: usize,
+    pub slab_pages: usize,
+    pub slab_objects: usize,
+    pub huge_pages: usize,
+    pub free_frames: usize,
+    pub total_frames: usize,
+}
+
+impl HeapStats {
+    ///
+    /// percentage of free arena memory outside the largest free block
+    ///
+    pub fn fragmentation(&self) -> usize {
+        if self.arena_free_bytes == 0 {
+            return 0;
+        }
+        100 - self.arena_largest_free * 100 / self.arena_free_bytes
+    }
+
+    pub fn dump(&self) {
+        sprint!("heap: {} bytes in {} allocations, peak {} bytes\n",
+                self.bytes_in_use, self.live_allocations, self.peak_bytes);
+        sprint!("heap: {} arenas, {} bytes free, {}% fragmented\n",
+                self.arenas, self.arena_free_bytes, self.fragmentation());
+        sprint!("heap: {} slab pages holding {} objects, {} huge pages\n",
+                self.slab_pages, self.slab_objects, self.huge_pages);
+        sprint!("frames: {} of {} free\n", self.free_frames, self.total_frames);
+    }
 }
 
 struct Arena {
     next: AtomicPtr<Arena>,
-    blocks: Mutex<*mut Block>
+    blocks: IrqMutex<*mut Block>
 }
 
 const BLOCK_MAGIC: usize = 0xdeadbeef;
@@ -43,73 +126,325 @@ pub struct Block {
     free: bool,
     next: *mut Block,
     arena: *mut Arena,
+    // page run backing a huge block
+    origin: usize,
+    pages: usize,
     magic: usize
 }
 
 const MIN_BLOCK_SIZE: usize = 8;
 const MAX_BLOCK_SIZE: usize = 3072;
+// every block payload is at least this aligned
+const MIN_ALIGN: usize = 16;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Tier {
+    Slab(usize),
+    Arena,
+    Huge,
+}
+
+fn tier_of(len: usize, align: usize) -> Tier {
+    let align = if align < MIN_ALIGN { MIN_ALIGN } else { align };
+    if let Some(class) = slab::class_for(len, align) {
+        return Tier::Slab(class);
+    }
+    // arenas are a single page, large alignments would not fit
+    if len > MAX_BLOCK_SIZE || (align > MIN_ALIGN && len + align > MAX_BLOCK_SIZE) {
+        return Tier::Huge;
+    }
+    Tier::Arena
+}
 
 impl HeapAllocator {
-    pub fn allocate(&self, len: usize) -> usize {
-        if len > MAX_BLOCK_SIZE {
-            return self.allocate_huge(len)
+    pub fn allocate(&self, len: usize, align: usize) -> usize {
+        match self.try_allocate(len, align) {
+            Ok(ret) => ret,
+            Err(_) => panic!("OOM! heap allocation of {} bytes", len),
+        }
+    }
+
+    pub fn try_allocate(&self, len: usize, align: usize) -> Result<usize, OutOfMemory> {
+        let ret = self.allocate_checked(len, align)?;
+        self.live.fetch_add(1, Ordering::Relaxed);
+        self.account(len, 0);
+        Ok(ret)
+    }
+
+    ///
+    /// for callers that must not wait for the shrink hooks,
+    /// it fails rather than panics
+    ///
+    pub fn try_allocate_atomic(&self, len: usize, align: usize) -> Result<usize, OutOfMemory> {
+        let _int = InterruptGuard::disable_interrupt();
+        self.try_allocate(len, align)
+    }
+
+    pub fn deallocate(&self, ptr: *mut u8, len: usize, align: usize) {
+        self.deallocate_checked(ptr, len, align);
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        self.account(0, len);
+    }
+
+    ///
+    /// gives cached memory that holds no allocations back to the frame
+    /// allocator, returns the number of frames
+    ///
+    pub fn shrink(&self) -> usize {
+        self.slabs.shrink()
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let mut arenas = 0;
+        let mut free_bytes = 0;
+        let mut largest = 0;
+        if let Some(first) = unsafe { self.arenas.load(Ordering::SeqCst).as_ref() } {
+            for arena in first.iter() {
+                arenas += 1;
+                let guard = arena.blocks.lock();
+                let mut r: *mut Block = *guard;
+                while let Some(block) = unsafe { r.as_ref() } {
+                    free_bytes += block.length;
+                    if block.length > largest {
+                        largest = block.length;
+                    }
+                    r = block.next;
+                }
+            }
+        }
+        let (slab_pages, slab_objects) = self.slabs.usage();
+        HeapStats {
+            bytes_in_use: self.in_use.load(Ordering::Relaxed),
+            peak_bytes: self.peak.load(Ordering::Relaxed),
+            live_allocations: self.live.load(Ordering::Relaxed),
+            arenas: arenas,
+            arena_free_bytes: free_bytes,
+            arena_largest_free: largest,
+            slab_pages: slab_pages,
+            slab_objects: slab_objects,
+            huge_pages: self.huge_pages.load(Ordering::Relaxed),
+            free_frames: FRAME.free(),
+            total_frames: FRAME.total(),
+        }
+    }
+
+    #[cfg(not(feature = "debug_heap"))]
+    fn allocate_checked(&self, len: usize, align: usize) -> Result<usize, OutOfMemory> {
+        self.allocate_raw(len, align)
+    }
+
+    #[cfg(not(feature = "debug_heap"))]
+    fn deallocate_checked(&self, ptr: *mut u8, len: usize, align: usize) {
+        self.deallocate_raw(ptr, len, align)
+    }
+
+    ///
+    /// wraps the payload in red zones, see debug_heap
+    ///
+    #[cfg(feature = "debug_heap")]
+    fn allocate_checked(&self, len: usize, align: usize) -> Result<usize, OutOfMemory> {
+        let raw = self.allocate_raw(debug_heap::padded(len, align), align)?;
+        Ok(debug_heap::arm(raw, len, align, track::callers()))
+    }
+
+    #[cfg(feature = "debug_heap")]
+    fn deallocate_checked(&self, ptr: *mut u8, len: usize, align: usize) {
+        if let Some((raw, raw_len, raw_align)) = debug_heap::release(ptr as usize, len, align) {
+            self.deallocate_raw(raw as *mut u8, raw_len, raw_align);
+        }
+    }
+
+    fn account(&self, added: usize, removed: usize) {
+        let now = if added >= removed {
+            self.in_use.fetch_add(added - removed, Ordering::Relaxed) + added - removed
+        } else {
+            self.in_use.fetch_sub(removed - added, Ordering::Relaxed) - (removed - added)
+        };
+        let mut peak = self.peak.load(Ordering::Relaxed);
+        while now > peak {
+            let old = self.peak.compare_and_swap(peak, now, Ordering::Relaxed);
+            if old == peak {
+                break;
+            }
+            peak = old;
+        }
+    }
+
+    fn allocate_raw(&self, len: usize, align: usize) -> Result<usize, OutOfMemory> {
+        assert!(align.is_power_of_two());
+        let align = if align < MIN_ALIGN { MIN_ALIGN } else { align };
+        match tier_of(len, align) {
+            Tier::Slab(class) => return self.slabs.allocate(class),
+            Tier::Huge => return self.allocate_huge(len, align),
+            Tier::Arena => {}
         }
         if let Some(first) = unsafe { self.arenas.load(Ordering::SeqCst).as_mut() } {
             for current in first.iter() {
-                match current.allocate(len) {
+                match current.allocate(len, align) {
                     Some(x) => {
                         //    kprint!("allocate 0x{:x} size = {}\n", x as usize, len);
-                        return x
+                        return Ok(x)
                     },
                     None => continue,
                 }
             }
         } else {
-            self.arenas.store(Arena::new(), Ordering::SeqCst);
-            return self.allocate(len);
+            self.arenas.store(Arena::new()?, Ordering::SeqCst);
+            return self.allocate_raw(len, align);
         }
 
         let mut last: &Arena = unsafe { self.arenas.load(Ordering::SeqCst).as_mut().unwrap().iter() }.last().unwrap();
-        let new_arena = Arena::new();
+        let new_arena = Arena::new()?;
 
-        let ret = new_arena.allocate(len).unwrap();
+        let ret = new_arena.allocate(len, align).unwrap();
         while last.next.compare_and_swap(ptr::null_mut(), new_arena, Ordering::SeqCst).is_null() == false {
             let new_last = last.iter().last().unwrap();
             last = new_last;
         }
         //last.next.store(new_arena, Ordering::SeqCst);
 
-        ret
+        Ok(ret)
     }
 
-    pub fn deallocate(&self, ptr: *mut u8, len: usize) {
+    fn deallocate_raw(&self, ptr: *mut u8, len: usize, align: usize) {
+        if let Tier::Slab(_) = tier_of(len, align) {
+            self.slabs.deallocate(ptr as usize);
+            return;
+        }
         //kprint!("free?\n");
         let block: &mut Block = unsafe {
             transmute::<_, *mut Block>(ptr.offset(0 - aligned_size!(Block) as isize))
                 .as_mut().unwrap()
         };
         //kprint!("free 0x{:x} size = {}\n", ptr as usize, block.length);
-        if block.length > MAX_BLOCK_SIZE {
+        if block.arena.is_null() {
             // is the a huge block?
-            let page_head: *const _ = block;
-            FRAME.dealloc_multiple(page_head as usize);
+            assert!(block.magic == BLOCK_MAGIC);
+            FRAME.dealloc_multiple(block.origin, block.pages);
+            self.huge_pages.fetch_sub(block.pages, Ordering::Relaxed);
             return;
         }
 
         block.free();
     }
 
-    pub fn allocate_huge(&self, len: usize) -> usize {
-        kprint!("allocate_huge! len = {}\n", len);
-        assert!(len > MAX_BLOCK_SIZE);
-        let page_cnt: usize = (len + aligned_size!(Block) - 1) / 4096 + 1;
-        assert!(page_cnt * 4096 >= len + aligned_size!(Block));
-        let mut blk = Block::create(::mem::FRAME.alloc_multiple(page_cnt));
+    ///
+    /// resizes without moving, returns false if that is not possible
+    ///
+    pub fn reallocate_inplace(&self, ptr: *mut u8, old_len: usize, len: usize, align: usize) -> bool {
+        if self.resize_raw(ptr, old_len, len, align) {
+            self.account(len, old_len);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn resize_raw(&self, ptr: *mut u8, old_len: usize, len: usize, align: usize) -> bool {
+        // the red zones have to move with the end of the payload
+        if cfg!(feature = "debug_heap") {
+            return false;
+        }
+        match (tier_of(old_len, align), tier_of(len, align)) {
+            (Tier::Slab(old_class), Tier::Slab(class)) => old_class == class,
+            (Tier::Arena, Tier::Arena) => {
+                let block: &mut Block = Block::create(ptr as usize - aligned_size!(Block));
+                block.resize(len)
+            },
+            (Tier::Huge, Tier::Huge) => {
+                let block: &mut Block = Block::create(ptr as usize - aligned_size!(Block));
+                let before = block.pages;
+                if !block.resize_huge(ptr as usize, len) {
+                    return false;
+                }
+                self.huge_pages.fetch_add(block.pages, Ordering::Relaxed);
+                self.huge_pages.fetch_sub(before, Ordering::Relaxed);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn reallocate(&self, ptr: *mut u8, old_len: usize, len: usize, align: usize) -> usize {
+        match self.try_reallocate(ptr, old_len, len, align) {
+            Ok(ret) => ret,
+            Err(_) => panic!("OOM! heap reallocation to {} bytes", len),
+        }
+    }
+
+    ///
+    /// the old block is left alone if this fails
+    ///
+    pub fn try_reallocate(&self, ptr: *mut u8, old_len: usize, len: usize, align: usize) -> Result<usize, OutOfMemory> {
+        if self.reallocate_inplace(ptr, old_len, len, align) {
+            return Ok(ptr as usize);
+        }
+        let huge = tier_of(old_len, align) == Tier::Huge && tier_of(len, align) == Tier::Huge;
+        let new_addr = if huge && !cfg!(feature = "debug_heap") {
+            self.remap_huge(ptr as usize, len, align)?
+        } else {
+            let new_addr = self.allocate_checked(len, align)?;
+            let copy_len = if old_len < len { old_len } else { len };
+            unsafe { memmove(new_addr as *mut u8, ptr, copy_len); }
+            self.deallocate_checked(ptr, old_len, align);
+            new_addr
+        };
+        self.account(len, old_len);
+        Ok(new_addr)
+    }
+
+    ///
+    /// grows a huge block by moving its pages to a bigger virtual range,
+    /// the contents are never copied
+    ///
+    fn remap_huge(&self, payload: usize, len: usize, align: usize) -> Result<usize, OutOfMemory> {
+        let (origin, old_pages) = {
+            let block: &Block = Block::create(payload - aligned_size!(Block));
+            assert!(block.arena.is_null() && block.magic == BLOCK_MAGIC);
+            (block.origin, block.pages)
+        };
+        let offset = payload - origin;
+        let pages = (offset + len - 1) / 4096 + 1;
+        // same placement rules as allocate_huge
+        let new_origin = if align <= 4096 {
+            FRAME.remap_multiple(origin, old_pages, pages, 4096, 0)?
+        } else {
+            FRAME.remap_multiple(origin, old_pages, pages, align, 4096)?
+        };
+
+        let block: &mut Block = Block::create(new_origin + offset - aligned_size!(Block));
+        block.origin = new_origin;
+        block.pages = pages;
+        block.length = len;
+        self.huge_pages.fetch_add(pages - old_pages, Ordering::Relaxed);
+        Ok(new_origin + offset)
+    }
+
+    pub fn allocate_huge(&self, len: usize, align: usize) -> Result<usize, OutOfMemory> {
+        let (origin, pages, payload) = if align <= 4096 {
+            // header right in front of the payload, both in the first page
+            let offset = align_up(aligned_size!(Block), align);
+            let pages = (offset + len - 1) / 4096 + 1;
+            let origin = FRAME.try_alloc_multiple(pages)?;
+            (origin, pages, origin + offset)
+        } else {
+            // the header gets a page of its own just below the aligned payload
+            let pages = (len - 1) / 4096 + 2;
+            let origin = FRAME.try_alloc_multiple_aligned(pages, align, 4096)?;
+            (origin, pages, origin + 4096)
+        };
+        assert!(payload % align == 0);
+
+        let mut blk = Block::create(payload - aligned_size!(Block));
         blk.arena = ptr::null_mut();
         blk.free = false;
         blk.length = len;
         blk.next = ptr::null_mut();
-        unsafe { transmute::<&mut Block, usize>(blk) + aligned_size!(Block) }
+        blk.origin = origin;
+        blk.pages = pages;
+        blk.magic = BLOCK_MAGIC;
+        self.huge_pages.fetch_add(pages, Ordering::Relaxed);
+        Ok(payload)
     }
 }
 
@@ -132,8 +467,8 @@ impl<'a> Iterator for ArenaIter<'a>{
 }
 
 impl Arena {
-    pub fn new<'a>() -> &'a mut Arena {
-        let addr = FRAME.alloc();
+    pub fn new<'a>() -> Result<&'a mut Arena, OutOfMemory> {
+        let addr = ::mem::paging::phys_to_virt(FRAME.try_alloc()?);
         let new_arena = unsafe {transmute::<usize, &mut Arena>(addr)};
         new_arena.next = AtomicPtr::new(ptr::null_mut());
 
@@ -143,19 +478,21 @@ impl Arena {
         new_block.free = true;
         new_block.next = ptr::null_mut();
         new_block.arena = new_arena;
+        new_block.origin = 0;
+        new_block.pages = 0;
         new_block.magic = BLOCK_MAGIC;
-        new_arena.blocks = Mutex::new(new_block);
-        new_arena
+        new_arena.blocks = IrqMutex::new(new_block);
+        Ok(new_arena)
     }
 
-    pub fn allocate(&self, len: usize) -> Option<usize> {
+    pub fn allocate(&self, len: usize, align: usize) -> Option<usize> {
         let mut len = ((len - 1) / 16) * 16 + 16;
         //kprint!("{:x}\n", unsafe {transmute::<_, usize>(&self.blocks)});
         let try_lock = self.blocks.try_lock();
         if try_lock.is_none() {
             return None;
         }
-        let mut guard: MutexGuard<*mut Block> = try_lock.unwrap();
+        let mut guard: IrqMutexGuard<*mut Block> = try_lock.unwrap();
         //kprint!("gwa!\n");
         let mut r: *mut Block = *guard;
         let mut previous: Option<*mut Block> = None;
@@ -164,7 +501,21 @@ impl Arena {
             pointer_sanity!(this_block);
             assert!(this_block.magic == BLOCK_MAGIC);
             assert!(this_block.free == true);
-            if this_block.length >= len {
+
+            // leading padding must be able to hold a free block of its own
+            let payload = r as usize + aligned_size!(Block);
+            let mut pad = align_up(payload, align) - payload;
+            if pad != 0 && pad < aligned_size!(Block) + MIN_BLOCK_SIZE {
+                pad = align_up(payload + aligned_size!(Block) + MIN_BLOCK_SIZE, align) - payload;
+            }
+
+            if this_block.length >= pad + len {
+                let mut previous = previous;
+                let mut this_block = this_block;
+                if pad != 0 {
+                    previous = Some(r);
+                    this_block = this_block.split_front(pad);
+                }
                 this_block.free = false;
                 this_block.shrink_to_fit(len);
                 if let Some(previous) = previous {
@@ -201,6 +552,48 @@ impl Block {
     }
 
 
+    ///
+    /// unmaps pages past the new end of a huge block, or maps more
+    /// if the virtual range behind it is free
+    ///
+    pub fn resize_huge(&mut self, payload: usize, len: usize) -> bool {
+        assert!(self.arena.is_null());
+        assert!(self.magic == BLOCK_MAGIC);
+        let pages = (payload - self.origin + len - 1) / 4096 + 1;
+        if pages < self.pages {
+            FRAME.dealloc_multiple(self.origin + pages * 4096, self.pages - pages);
+        } else if pages > self.pages {
+            if !FRAME.extend_multiple(self.origin + self.pages * 4096, pages - self.pages) {
+                return false;
+            }
+        }
+        self.pages = pages;
+        self.length = len;
+        true
+    }
+
+    ///
+    /// the first `pad` bytes stay behind as a free block,
+    /// returns the free block that follows them
+    ///
+    pub fn split_front<'b>(&mut self, pad: usize) -> &'b mut Block {
+        assert!(pad >= aligned_size!(Block) + MIN_BLOCK_SIZE && pad % MIN_ALIGN == 0);
+        assert!(self.length >= pad);
+        let base_addr: usize = self as *mut Block as usize;
+        let new_block: &mut Block = Block::create(base_addr + pad);
+        pointer_sanity!(new_block);
+        new_block.next = self.next;
+        new_block.length = self.length - pad;
+        new_block.free = true;
+        new_block.arena = self.arena;
+        new_block.origin = 0;
+        new_block.pages = 0;
+        new_block.magic = BLOCK_MAGIC;
+        self.next = new_block;
+        self.length = pad - aligned_size!(Block);
+        new_block
+    }
+
     pub fn shrink_to_fit(&mut self, target: usize) {
         if self.length < aligned_size!(Block) + MIN_BLOCK_SIZE + target {
             return;
@@ -215,6 +608,8 @@ impl Block {
             assert!(new_block.length < 4096);
             new_block.free = true;
             new_block.arena = self.arena;
+            new_block.origin = 0;
+            new_block.pages = 0;
 
             new_block.magic = BLOCK_MAGIC;
             self.next = new_block;
@@ -232,58 +627,110 @@ impl Block {
     pub fn free(&mut self) {
         assert!(!self.arena.is_null());
         assert!(self.magic == BLOCK_MAGIC);
+        assert!(!self.free, "double free of heap block 0x{:x}", self as *const Block as usize);
         unsafe {
             assert!(transmute_copy::<_, usize>(&self) - transmute::<_, usize>(self.arena) < 4096
                 && transmute_copy::<_, usize>(&self) > transmute::<_, usize>(self.arena));
         }
         // reinsert itself into arena
-        let mut guard: MutexGuard<*mut Block> = unsafe { self.arena.as_mut().unwrap().blocks.lock() };
+        let mut guard: IrqMutexGuard<*mut Block> = unsafe { self.arena.as_mut().unwrap().blocks.lock() };
+        self.insert_free(&mut *guard);
+    }
 
-        let mut r: *mut Block = *guard;
-        let mut previous: Option<*mut Block> = None;
+    ///
+    /// puts the block on the sorted free list of its arena
+    /// and merges it with both neighbours if they are free
+    ///
+    fn insert_free(&mut self, head: &mut *mut Block) {
+        let this: *mut Block = self;
+        let mut r: *mut Block = *head;
+        let mut previous: *mut Block = ptr::null_mut();
         while let Some(this_block) = unsafe { r.as_mut() } {
             pointer_sanity!(this_block);
             assert!(this_block.magic == BLOCK_MAGIC);
             assert!(this_block.free == true);
-            //kprint!("free\n");
-            if !Block::pointer_comparison(this_block, self)
-                || this_block.next == ptr::null_mut() {
+            if !Block::pointer_comparison(this_block, this) {
                 // found insertion point
-                self.next = this_block;
-                match previous {
-                    // insert at the head
-
-                    None => {
-                        *guard = self;
-                    },
-                    Some(prev) => {
-                        unsafe { prev.as_mut().unwrap().next = self; }
-                    }
-                }
-                self.free = true;
-                self.try_merge();
-                return;
+                break;
             }
-
-
-            previous = Some(r);
+            previous = r;
             r = this_block.next;
         }
 
-
-        self.next = ptr::null_mut();
-        match previous {
-            // insert at the end
-
-
+        self.next = r;
+        self.free = true;
+        match unsafe { previous.as_mut() } {
+            // insert at the head
             None => {
-                *guard = self;
+                *head = this;
             },
             Some(prev) => {
-                unsafe { prev.as_mut().unwrap().next = self; }
+                prev.next = this;
             }
         }
-        self.free = true;
+        self.try_merge();
+        if let Some(prev) = unsafe { previous.as_mut() } {
+            prev.try_merge();
+        }
+    }
+
+    fn unlink(&mut self, head: &mut *mut Block) {
+        let this: *mut Block = self;
+        if *head == this {
+            *head = self.next;
+        } else {
+            let mut r: *mut Block = *head;
+            while let Some(this_block) = unsafe { r.as_mut() } {
+                if this_block.next == this {
+                    this_block.next = self.next;
+                    break;
+                }
+                r = this_block.next;
+            }
+        }
+        self.next = ptr::null_mut();
+        self.free = false;
+    }
+
+    ///
+    /// grows into the free block right after this one, or gives back
+    /// the tail when shrinking. Returns false if the block has to move.
+    ///
+    pub fn resize(&mut self, len: usize) -> bool {
+        assert!(!self.arena.is_null());
+        assert!(self.magic == BLOCK_MAGIC);
+        let len = ((len - 1) / 16) * 16 + 16;
+        let arena_end = self.arena as usize + 4096;
+        let mut guard: IrqMutexGuard<*mut Block> = unsafe { self.arena.as_mut().unwrap().blocks.lock() };
+
+        if len > self.length {
+            let next_addr = self as *mut Block as usize + aligned_size!(Block) + self.length;
+            if next_addr + aligned_size!(Block) > arena_end {
+                return false;
+            }
+            // blocks tile the arena, so there is a header right behind us
+            let next: &mut Block = Block::create(next_addr);
+            assert!(next.magic == BLOCK_MAGIC);
+            if !next.free || self.length + aligned_size!(Block) + next.length < len {
+                return false;
+            }
+            next.unlink(&mut *guard);
+            self.length += aligned_size!(Block) + next.length;
+        }
+
+        if self.length >= len + aligned_size!(Block) + MIN_BLOCK_SIZE {
+            let tail: &mut Block = Block::create(self as *mut Block as usize + aligned_size!(Block) + len);
+            tail.length = self.length - len - aligned_size!(Block);
+            tail.free = false;
+            tail.next = ptr::null_mut();
+            tail.arena = self.arena;
+            tail.origin = 0;
+            tail.pages = 0;
+            tail.magic = BLOCK_MAGIC;
+            self.length = len;
+            tail.insert_free(&mut *guard);
+        }
+        true
     }
 
     ///
@@ -318,3 +765,105 @@ impl Block {
         }
     }
 }
+
+pub fn test_alignment() {
+    for &align in [32, 64, 4096, 8192].iter() {
+        let mut ptrs = [0usize; 4];
+        for (i, &len) in [8, 100, 2000, 10000].iter().enumerate() {
+            let p = HEAP.allocate(len, align);
+            assert!(p % align == 0, "0x{:x} not aligned to {}", p, align);
+            unsafe { ::rlibc::memset(p as *mut u8, 0xab, len) };
+            ptrs[i] = p;
+        }
+        for (i, &len) in [8, 100, 2000, 10000].iter().enumerate() {
+            HEAP.deallocate(ptrs[i] as *mut u8, len, align);
+        }
+    }
+    kprint!("heap alignment test successful\n");
+}
+
+pub fn test_stats() {
+    let before = HEAP.stats();
+    let lens = [24, 1000, 3000, 20000];
+    let mut ptrs = [0usize; 4];
+    for (i, &len) in lens.iter().enumerate() {
+        ptrs[i] = HEAP.allocate(len, 16);
+    }
+    let during = HEAP.stats();
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 24 + 1000 + 3000 + 20000);
+    assert_eq!(during.live_allocations, before.live_allocations + 4);
+    assert!(during.peak_bytes >= during.bytes_in_use);
+    assert!(during.huge_pages > before.huge_pages);
+    for (i, &len) in lens.iter().enumerate() {
+        HEAP.deallocate(ptrs[i] as *mut u8, len, 16);
+    }
+    let after = HEAP.stats();
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    // the debug heap keeps freed blocks in its quarantine
+    if !cfg!(feature = "debug_heap") {
+        assert_eq!(after.huge_pages, before.huge_pages);
+    }
+    after.dump();
+    kprint!("heap accounting test successful\n");
+}
+
+pub fn test_oom() {
+    let free = FRAME.free();
+    let too_big = (FRAME.total() + 1) * 4096;
+    assert_eq!(HEAP.try_allocate(too_big, 16), Err(OutOfMemory));
+    assert!(::mem::alloc_stub::__rust_allocate(too_big, 16).is_null());
+    // the shrink hooks may have given frames back, but none were lost
+    assert!(FRAME.free() >= free);
+    let ptr = HEAP.try_allocate(64, 16).unwrap();
+    HEAP.deallocate(ptr as *mut u8, 64, 16);
+    kprint!("out-of-memory test successful\n");
+}
+
+pub fn test_atomic() {
+    use super::buddy::MAX_ORDER;
+    use super::paging::phys_to_virt;
+
+    // the frames are chained through their first word
+    // and remember their order in the second
+    fn push(chain: &mut usize, frame: usize, order: usize) {
+        let words = phys_to_virt(frame) as *mut usize;
+        unsafe {
+            *words = *chain;
+            *words.offset(1) = order;
+        }
+        *chain = frame;
+    }
+
+    let ptr = HEAP.try_allocate_atomic(64, 16).unwrap();
+    HEAP.deallocate(ptr as *mut u8, 64, 16);
+
+    let _int = InterruptGuard::disable_interrupt();
+    assert!(oom::atomic());
+    let free = FRAME.free();
+    let emergency = FRAME.emergency_left();
+    assert!(emergency > 0);
+
+    // empty the buddy allocator, then the pool
+    let mut chain = 0;
+    for order in (0..MAX_ORDER + 1).rev() {
+        while let Ok(frame) = FRAME.try_alloc_order(order) {
+            push(&mut chain, frame, order);
+        }
+    }
+    assert_eq!(FRAME.emergency_left(), 0);
+
+    // frees refill the pool before anything else
+    while chain != 0 {
+        let words = phys_to_virt(chain) as *const usize;
+        let (next, order) = unsafe { (*words, *words.offset(1)) };
+        if order == 0 {
+            FRAME.dealloc(chain);
+        } else {
+            FRAME.dealloc_order(chain, order);
+        }
+        chain = next;
+    }
+    assert_eq!(FRAME.emergency_left(), emergency);
+    assert_eq!(FRAME.free(), free);
+    kprint!("atomic allocation test successful\n");
+}
@@ -0,0 +1,181 @@
+use core::mem::size_of;
+use core::slice;
+use core::str;
+
+const MAX_REGIONS: usize = 64;
+
+const TAG_END: u32 = 0;
+const TAG_COMMAND_LINE: u32 = 1;
+const TAG_MEMORY_MAP: u32 = 6;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RegionKind {
+    Usable,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    Bad,
+}
+
+impl RegionKind {
+    fn from_multiboot(typ: u32) -> RegionKind {
+        match typ {
+            1 => RegionKind::Usable,
+            3 => RegionKind::AcpiReclaimable,
+            4 => RegionKind::AcpiNvs,
+            5 => RegionKind::Bad,
+            _ => RegionKind::Reserved,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryRegion {
+    pub base: usize,
+    pub len: usize,
+    pub kind: RegionKind,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> usize {
+        self.base + self.len
+    }
+
+    pub fn contains(&self, paddr: usize) -> bool {
+        paddr >= self.base && paddr < self.end()
+    }
+}
+
+#[repr(C)]
+struct TagHeader {
+    typ: u32,
+    size: u32,
+}
+
+#[repr(C)]
+struct MemoryMapEntry {
+    base_addr: u64,
+    length: u64,
+    typ: u32,
+    reserved: u32,
+}
+
+///
+/// The full multiboot2 memory map, sorted by base address.
+/// The multiboot2 crate only hands out the available areas,
+/// so the tag is walked by hand here.
+///
+#[derive(Copy, Clone)]
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_REGIONS],
+    cnt: usize,
+}
+
+impl MemoryMap {
+    ///
+    /// `info` is the virtual address of the multiboot information
+    ///
+    pub fn from_multiboot(info: usize) -> MemoryMap {
+        let mut ret = MemoryMap {
+            regions: [MemoryRegion { base: 0, len: 0, kind: RegionKind::Reserved }; MAX_REGIONS],
+            cnt: 0,
+        };
+
+        let total_size = unsafe { *(info as *const u32) } as usize;
+        let mut tag_addr = info + 8;
+        while tag_addr < info + total_size {
+            let tag: &TagHeader = unsafe { &*(tag_addr as *const TagHeader) };
+            if tag.typ == TAG_END {
+                break;
+            }
+            if tag.typ == TAG_MEMORY_MAP {
+                let entry_size = unsafe { *((tag_addr + 8) as *const u32) } as usize;
+                let mut entry_addr = tag_addr + 16;
+                while entry_addr + size_of::<MemoryMapEntry>() <= tag_addr + tag.size as usize {
+                    let entry: &MemoryMapEntry = unsafe { &*(entry_addr as *const MemoryMapEntry) };
+                    ret.push(MemoryRegion {
+                        base: entry.base_addr as usize,
+                        len: entry.length as usize,
+                        kind: RegionKind::from_multiboot(entry.typ),
+                    });
+                    entry_addr += entry_size;
+                }
+            }
+            // tags are 8 byte aligned
+            tag_addr += ((tag.size as usize + 7) / 8) * 8;
+        }
+        ret.sort();
+        ret
+    }
+
+    fn push(&mut self, region: MemoryRegion) {
+        if region.len == 0 {
+            return;
+        }
+        if self.cnt == MAX_REGIONS {
+            kprint!("memmap: too many regions, dropping 0x{:x}\n", region.base);
+            return;
+        }
+        self.regions[self.cnt] = region;
+        self.cnt += 1;
+    }
+
+    fn sort(&mut self) {
+        for i in 1..self.cnt {
+            let mut j = i;
+            while j > 0 && self.regions[j - 1].base > self.regions[j].base {
+                self.regions.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.cnt]
+    }
+
+    pub fn region_of(&self, paddr: usize) -> Option<MemoryRegion> {
+        for r in self.regions() {
+            if r.contains(paddr) {
+                return Some(*r);
+            }
+        }
+        None
+    }
+
+    ///
+    /// end of the highest usable region
+    ///
+    pub fn usable_end(&self) -> usize {
+        let mut ret = 0;
+        for r in self.regions() {
+            if r.kind == RegionKind::Usable && r.end() > ret {
+                ret = r.end();
+            }
+        }
+        ret
+    }
+}
+
+///
+/// the kernel command line from the multiboot information at `info`,
+/// empty if the boot loader passed none
+///
+pub fn command_line(info: usize) -> &'static str {
+    let total_size = unsafe { *(info as *const u32) } as usize;
+    let mut tag_addr = info + 8;
+    while tag_addr < info + total_size {
+        let tag: &TagHeader = unsafe { &*(tag_addr as *const TagHeader) };
+        if tag.typ == TAG_END {
+            break;
+        }
+        if tag.typ == TAG_COMMAND_LINE {
+            // zero terminated after the header
+            let bytes = unsafe { slice::from_raw_parts((tag_addr + 8) as *const u8, tag.size as usize - 8) };
+            let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            return str::from_utf8(&bytes[..len]).unwrap_or("");
+        }
+        tag_addr += ((tag.size as usize + 7) / 8) * 8;
+    }
+    ""
+}
@@ -0,0 +1,133 @@
+use core::ptr::{read_volatile, write_volatile};
+use super::bitmap::Bitmap;
+use super::paging::phys_to_virt;
+
+// fills for the moving inversions, each one is also run inverted
+const PATTERNS: [u64; 3] = [0, 0x5555_5555_5555_5555, 0x0f0f_0f0f_0f0f_0f0f];
+// bad frames listed in the summary
+const MAX_REPORTED: usize = 16;
+const WORDS_PER_FRAME: usize = 4096 / 8;
+
+///
+/// A run of free frames `[first, end)` under test. Frames that fail a
+/// check get their bit in `bad` set, `found` counts them.
+///
+struct Run<'a, 'b: 'a> {
+    first: usize,
+    end: usize,
+    bad: &'a mut Bitmap<'b>,
+    found: usize,
+}
+
+impl<'a, 'b> Run<'a, 'b> {
+    fn word(&self, i: usize) -> *mut u64 {
+        (phys_to_virt(self.first * 4096) + i * 8) as *mut u64
+    }
+
+    fn words(&self) -> usize {
+        (self.end - self.first) * WORDS_PER_FRAME
+    }
+
+    fn fail(&mut self, i: usize, expected: u64, got: u64) {
+        let frame = self.first + i / WORDS_PER_FRAME;
+        if self.bad.get(frame) {
+            return;
+        }
+        self.bad.set(frame, true);
+        if self.found < MAX_REPORTED {
+            sprint!("memtest: bad frame 0x{:x}, expected 0x{:x} at 0x{:x}, read 0x{:x}\n",
+                    frame * 4096, expected, frame * 4096 + (i % WORDS_PER_FRAME) * 8, got);
+        }
+        self.found += 1;
+    }
+
+    fn check(&mut self, i: usize, expected: u64) {
+        let got = unsafe { read_volatile(self.word(i)) };
+        if got != expected {
+            self.fail(i, expected, got);
+        }
+    }
+
+    ///
+    /// every word holds its own physical address, then its complement,
+    /// finds address lines that are stuck or shorted
+    ///
+    fn address_in_address(&mut self) {
+        for &invert in [false, true].iter() {
+            let base = (self.first * 4096) as u64;
+            let value = |i: usize| {
+                let addr = base + i as u64 * 8;
+                if invert { !addr } else { addr }
+            };
+            for i in 0..self.words() {
+                unsafe { write_volatile(self.word(i), value(i)) };
+            }
+            for i in 0..self.words() {
+                self.check(i, value(i));
+            }
+        }
+    }
+
+    ///
+    /// fill with `p`; upwards check `p` and write `!p`; downwards check
+    /// `!p` and write `p`. Finds cells that flip their neighbours.
+    ///
+    fn moving_inversions(&mut self, p: u64) {
+        let n = self.words();
+        for i in 0..n {
+            unsafe { write_volatile(self.word(i), p) };
+        }
+        for i in 0..n {
+            self.check(i, p);
+            unsafe { write_volatile(self.word(i), !p) };
+        }
+        for i in (0..n).rev() {
+            self.check(i, !p);
+            unsafe { write_volatile(self.word(i), p) };
+        }
+        for i in 0..n {
+            self.check(i, p);
+        }
+    }
+}
+
+///
+/// Tests every frame whose bit is clear in `freemap` and sets the bits
+/// of those that fail, so they are never handed out. The memory has to
+/// be in the direct map already. Returns the number of bad frames.
+///
+pub fn run(freemap: &mut Bitmap, frame_cnt: usize) -> usize {
+    sprint!("memtest: testing free memory, this takes a while\n");
+    let mut tested = 0;
+    let mut found = 0;
+    let mut frame = 0;
+    while frame < frame_cnt {
+        if freemap.get(frame) {
+            frame += 1;
+            continue;
+        }
+        let first = frame;
+        while frame < frame_cnt && !freemap.get(frame) {
+            frame += 1;
+        }
+        found = {
+            let mut run = Run {
+                first: first,
+                end: frame,
+                bad: &mut *freemap,
+                found: found,
+            };
+            run.address_in_address();
+            for p in PATTERNS.iter() {
+                run.moving_inversions(*p);
+                run.moving_inversions(!*p);
+            }
+            run.found
+        };
+        tested += frame - first;
+        sprint!("memtest: 0x{:x}-0x{:x} done\n", first * 4096, frame * 4096);
+    }
+    sprint!("memtest: {} MiB tested, {} bad frames{}\n", tested * 4096 >> 20, found,
+            if found > MAX_REPORTED { ", only the first ones are listed" } else { "" });
+    found
+}
@@ -6,35 +6,104 @@ use core::cell;
 use devices::vga;
 
 pub mod bitmap;
+pub mod buddy;
 pub mod frame;
 pub mod paging;
+pub mod dma;
+pub mod address_space;
+pub mod vma;
+pub mod shootdown;
+pub mod pcid;
+pub mod oom;
+pub mod stacks;
+pub mod swap;
 pub mod heap_allocator;
+pub mod slab;
 pub mod alloc_stub;
+pub mod vrange;
+pub mod memmap;
+pub mod memtest;
+pub mod track;
+#[cfg(feature = "debug_heap")]
+pub mod debug_heap;
 
 
 
 
 
+// virtual address of the multiboot information
 pub static mut BOOTINFO: usize = 0;
 lazy_static! {
 
     pub static ref FRAME: frame::FrameAllocator<'static> = unsafe {
         assert!(BOOTINFO != 0);
-        let (base, size) = parse_multiboot(BOOTINFO);
-        frame::FrameAllocator::new(base, size)
+        let (map, kernel_end) = parse_multiboot(BOOTINFO);
+        frame::FrameAllocator::new(map, kernel_end, has_flag(BOOTINFO, "memtest"))
     };
 }
 
-pub fn parse_multiboot(paddr: usize) -> (usize, usize) {
-    let bootinfo = unsafe {multiboot2::load(paddr)};
-    let memtag = bootinfo.memory_map_tag().expect("cannot find memory tag");
+///
+/// Maps every kernel section with the permissions from its ELF flags:
+/// code is read-only and executable, everything writable is never
+/// executable, the rest is read-only. The boot sections below the
+/// kernel image only live in the identity map and are left alone.
+///
+pub fn protect_kernel(info: usize) {
+    use self::paging::*;
+    use multiboot2::{ELF_SECTION_WRITABLE, ELF_SECTION_EXECUTABLE};
+    let bootinfo = unsafe { multiboot2::load(info) };
+    let elftag = bootinfo.elf_sections_tag().expect("cannot find elf tag");
+    for section in elftag.sections() {
+        if !section.is_allocated() || section.start_address() < KERNEL_BASE {
+            continue;
+        }
+        let mut prot = READ;
+        if section.flags().contains(ELF_SECTION_WRITABLE) {
+            prot = prot | WRITE;
+        } else if section.flags().contains(ELF_SECTION_EXECUTABLE) {
+            prot = prot | EXEC;
+        }
+        let mut page = section.start_address() & !0xfff;
+        while page < section.end_address() {
+            page_protect(page, prot, CacheMode::WriteBack);
+            page += 4096;
+        }
+    }
+    kprint!("kernel sections protected, nx {}\n", if nx_enabled() { "on" } else { "off" });
+}
+
+///
+/// whether `flag` is one of the words on the kernel command line
+///
+pub fn has_flag(info: usize, flag: &str) -> bool {
+    memmap::command_line(info).split(' ').any(|w| w == flag)
+}
+
+///
+/// what follows `name=` on the kernel command line
+///
+pub fn flag_value(info: usize, name: &str) -> Option<&'static str> {
+    for w in memmap::command_line(info).split(' ') {
+        if w.len() > name.len() && w.starts_with(name) && w.as_bytes()[name.len()] == b'=' {
+            return Some(&w[name.len() + 1..]);
+        }
+    }
+    None
+}
+
+///
+/// returns the memory map and the first physical address
+/// not taken by the kernel image or the boot information
+///
+pub fn parse_multiboot(info: usize) -> (memmap::MemoryMap, usize) {
+    let bootinfo = unsafe {multiboot2::load(info)};
+    let map = memmap::MemoryMap::from_multiboot(info);
     kprint!("memory info:\n");
-    let mut mem_upper_bd: usize = 0;
-    for area in memtag.memory_areas() {
-        kprint!("start: 0x{:x}, length: 0x{:x}\n",
-                 area.base_addr,
-                 area.length);
-        mem_upper_bd = (area.base_addr + area.length) as usize;
+    for region in map.regions() {
+        kprint!("start: 0x{:x}, length: 0x{:x}, {:?}\n",
+                 region.base,
+                 region.len,
+                 region.kind);
     }
 
     let elftag = bootinfo.elf_sections_tag().expect("cannot find elf tag");
@@ -47,9 +116,16 @@ pub fn parse_multiboot(paddr: usize) -> (usize, usize) {
         kprint!("section start: 0x{:x} end: 0x{:x}\n",
                 section.start_address(),
                 section.end_address());
-        mem_lower_bd = section.end_address();
+        // the boot sections are linked at their physical address
+        let mut end = section.end_address();
+        if end > paging::KERNEL_BASE {
+            end -= paging::KERNEL_BASE;
+        }
+        if end > mem_lower_bd {
+            mem_lower_bd = end;
+        }
     }
-    let boot_end = bootinfo.end_address();
+    let boot_end = paging::virt_to_phys(bootinfo.end_address());
     mem_lower_bd = if boot_end > mem_lower_bd {
         boot_end
     } else {
@@ -57,5 +133,5 @@ pub fn parse_multiboot(paddr: usize) -> (usize, usize) {
     };
 
     kprint!("available memory starts at 0x{:x}\n", mem_lower_bd);
-    (mem_lower_bd,  mem_upper_bd - mem_lower_bd)
+    (map, mem_lower_bd)
 }
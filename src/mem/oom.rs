@@ -0,0 +1,83 @@
+use spin::Mutex;
+use core::sync::atomic::*;
+use interrupt::guard::interrupts_enabled;
+
+const MAX_HOOKS: usize = 8;
+
+///
+/// An allocation failed, even after the shrink hooks were run.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OutOfMemory;
+
+///
+/// Asked to give back about `frames` frames, returns how many it freed.
+/// Hooks run in whatever context the failing allocation was made in, so
+/// they must not allocate and must not block on locks that allocation
+/// paths hold; skip whatever is busy. They never run for atomic
+/// allocations.
+///
+pub type ShrinkHook = fn(usize) -> usize;
+
+#[derive(Copy, Clone)]
+struct Hook {
+    name: &'static str,
+    shrink: ShrinkHook,
+}
+
+static HOOKS: Mutex<[Option<Hook>; MAX_HOOKS]> = Mutex::new([None; MAX_HOOKS]);
+
+// set while the hooks run, allocations failing inside them just fail
+static SHRINKING: AtomicBool = ATOMIC_BOOL_INIT;
+
+///
+/// registers a subsystem that can drop cached memory under pressure
+///
+pub fn register(name: &'static str, shrink: ShrinkHook) {
+    let mut hooks = HOOKS.lock();
+    for slot in hooks.iter_mut() {
+        if slot.is_none() {
+            *slot = Some(Hook { name: name, shrink: shrink });
+            return;
+        }
+    }
+    panic!("oom: no room for shrink hook {}", name);
+}
+
+///
+/// Whether allocations made right now are atomic: interrupts are off on
+/// this cpu, so this is an interrupt handler, the scheduler or code
+/// holding an IrqMutex. Atomic allocations skip the shrink hooks, which
+/// may wait on locks the interrupted code holds or on disk I/O, and may
+/// take frames from the emergency pool instead.
+///
+pub fn atomic() -> bool {
+    !interrupts_enabled()
+}
+
+///
+/// Runs the hooks in registration order until `frames` frames were
+/// given back. Returns the number freed, always 0 in atomic context.
+///
+pub fn shrink(frames: usize) -> usize {
+    if atomic() || SHRINKING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    // copied so the hooks run without the lock
+    let hooks = *HOOKS.lock();
+    let mut freed = 0;
+    for hook in hooks.iter() {
+        if freed >= frames {
+            break;
+        }
+        if let Some(h) = *hook {
+            let n = (h.shrink)(frames - freed);
+            if n > 0 {
+                sprint!("oom: {} gave back {} frames\n", h.name, n);
+            }
+            freed += n;
+        }
+    }
+    SHRINKING.store(false, Ordering::Release);
+    freed
+}
@@ -1,12 +1,34 @@
 use x86::shared::control_regs::*;
 use x86::shared::tlb;
+use x86::shared::msr::rdmsr;
 use core::slice;
 use core::mem::size_of;
 use core::intrinsics::atomic_cxchg;
+use core::sync::atomic::*;
 
 use bitflags;
 use core::option;
 use super::FRAME;
+use super::memmap::RegionKind;
+use super::address_space::KERNEL_SPACE;
+use devices::mmio::MMIO;
+
+// bits 12..51 of an entry hold the physical address
+pub const ADDRESS_MASK: usize = 0x000f_ffff_ffff_f000;
+
+// the kernel image is linked at -2 GiB, physical 0 shows up here
+pub const KERNEL_BASE: usize = 0xffff_ffff_8000_0000;
+
+// all physical memory is mapped linearly from here, in the first
+// slot of the upper half
+pub const PHYS_MAP_BASE: usize = 0xffff_8000_0000_0000;
+
+// boot.asm maps this much of the direct map with 2 MiB pages
+pub const BOOT_DIRECT_MAP: usize = 0x4000_0000;
+
+// end of the physical memory covered by the direct map,
+// 0 until map_physical_memory has run
+static DIRECT_MAP_END: AtomicUsize = ATOMIC_USIZE_INIT;
 
 #[derive(Copy, Clone)]
 pub struct Entry(usize);
@@ -23,10 +45,93 @@ bitflags! {
         const DIRTY =           1 << 6,
         const HUGE_PAGE =       1 << 7,
         const GLOBAL =          1 << 8,
+        // available to software: read-only mapping of a shared frame
+        const COPY_ON_WRITE =   1 << 9,
+        // not present, the address bits hold a swap slot
+        const SWAPPED =         1 << 10,
+        // along with SWAPPED while the frame is still being written out
+        const SWAP_IO =         1 << 11,
         const NO_EXECUTE =      1 << 63,
     }
 }
 
+bitflags! {
+    pub flags Protection: u8 {
+        const READ =  1 << 0,
+        const WRITE = 1 << 1,
+        const EXEC =  1 << 2,
+        const USER =  1 << 3,
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    Uncached,
+    /// for framebuffers, uncached without PAT support
+    WriteCombining,
+}
+
+const IA32_EFER: u32 = 0xC0000080;
+const EFER_NXE: u64 = 1 << 11;
+const CR4_LA57: usize = 1 << 12;
+
+lazy_static! {
+    // boot.asm turns it on for every cpu if it is there at all
+    static ref NX_ENABLED: bool = unsafe { rdmsr(IA32_EFER) & EFER_NXE != 0 };
+    // same for five-level paging, it can only be chosen before paging is on
+    static ref FIVE_LEVEL: bool = read_cr4() & CR4_LA57 != 0;
+}
+
+pub fn nx_enabled() -> bool {
+    *NX_ENABLED
+}
+
+pub fn five_level() -> bool {
+    *FIVE_LEVEL
+}
+
+///
+/// level of the top table: 3 for a PML4, 4 for a PML5
+///
+pub fn top_level() -> u8 {
+    if five_level() { 4 } else { 3 }
+}
+
+pub fn read_cr4() -> usize {
+    let ret: usize;
+    unsafe { asm!("mov $0, cr4" : "=r"(ret) ::: "intel", "volatile") };
+    ret
+}
+
+pub unsafe fn write_cr4(val: usize) {
+    asm!("mov cr4, $0" :: "r"(val) : "memory" : "intel", "volatile");
+}
+
+///
+/// entry bits of a last level mapping with `prot` and `cache`
+///
+pub fn protection_flags(prot: Protection, cache: CacheMode) -> EntryFlags {
+    let mut flags = PRESENT;
+    if prot.contains(WRITE) {
+        flags = flags | WRITABLE;
+    }
+    if prot.contains(USER) {
+        flags = flags | USER_ACCESSIBLE;
+    }
+    if !prot.contains(EXEC) && nx_enabled() {
+        flags = flags | NO_EXECUTE;
+    }
+    match cache {
+        CacheMode::WriteBack => flags,
+        CacheMode::WriteThrough => flags | WRITE_THROUGH,
+        CacheMode::Uncached => flags | NO_CACHE | WRITE_THROUGH,
+        // boot.asm makes PAT entry 2 (PCD alone) write-combining
+        CacheMode::WriteCombining => flags | NO_CACHE,
+    }
+}
+
 impl Entry {
     pub fn flags(&self) -> EntryFlags {
         EntryFlags::from_bits_truncate(self.0)
@@ -34,94 +139,282 @@ impl Entry {
 
     pub fn set_paddr(&mut self, paddr: usize) {
         assert!(paddr % 4096 == 0);
-        self.0 |= paddr;
+        self.0 = (self.0 & !ADDRESS_MASK) | paddr;
     }
 
     pub fn paddr(&self) -> usize {
-        (self.0 >> 12) << 12
+        self.0 & ADDRESS_MASK
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.flags().contains(PRESENT)
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = 0;
     }
 
     pub fn set_flags(&mut self, flags: EntryFlags) {
         self.0 = flags.bits() | self.paddr()
     }
 
+    ///
+    /// turns a present entry into one for a page in swap `slot`,
+    /// the flags stay for when it comes back
+    ///
+    pub fn set_swapped(&mut self, slot: usize) {
+        let flags = (self.flags() - PRESENT) | SWAPPED;
+        self.0 = (slot << 12) | flags.bits();
+    }
+
+    pub fn swap_slot(&self) -> Option<usize> {
+        if !self.is_present() && self.flags().contains(SWAPPED) {
+            Some(self.paddr() >> 12)
+        } else {
+            None
+        }
+    }
+
+    ///
+    /// whether the page is on its way to swap and its slot not written yet
+    ///
+    pub fn swap_busy(&self) -> bool {
+        self.swap_slot().is_some() && self.flags().contains(SWAP_IO)
+    }
+
     pub fn to_int(&self) -> usize {
         self.0
     }
 
-    pub fn try_set_paddr(&mut self, paddr: usize) {
+    ///
+    /// replaces the entry by `new` if it still holds `old`,
+    /// returns whether it did
+    ///
+    pub fn compare_and_set(&mut self, old: usize, new: usize) -> bool {
+        unsafe {
+            let (_, ok) = atomic_cxchg(&mut self.0, old, new);
+            ::core::intrinsics::atomic_fence();
+            ok
+        }
+    }
+
+    ///
+    /// installs `paddr` unless somebody else got there first,
+    /// returns whether it was installed
+    ///
+    pub fn try_set_paddr(&mut self, paddr: usize) -> bool {
         let old: Entry = *self;
         let mut new: Entry = old;
         new.set_paddr(paddr);
         new.set_flags(PRESENT | WRITABLE);
         unsafe {
-            atomic_cxchg(&mut self.0, old.0, new.0);
+            let (_, ok) = atomic_cxchg(&mut self.0, old.0, new.0);
             ::core::intrinsics::atomic_fence();
+            ok
         }
     }
 }
 
+///
+/// The free functions below work on the kernel address space.
+/// Kernel mappings are shared by every address space, so this is the
+/// same as editing whatever `cr3()` points at for kernel addresses.
+///
+
+///
+/// kernel data, read-write and not executable
+///
 pub fn page_map(vaddr: usize, paddr: usize) -> Option<usize> {
-    match get_entry(vaddr, false) {
-        Some(_) => panic!("vaddr already in use. vaddr = {:x}", vaddr),
-        None => {
-            let entry: &mut Entry = get_entry(vaddr, true).unwrap();
-            entry.set_paddr(paddr);
-            entry.set_flags(PRESENT | WRITABLE);
-            unsafe {tlb::flush(vaddr)};
-            Some(vaddr)
-        }
-    }
+    KERNEL_SPACE.map_prot(vaddr, paddr, READ | WRITE, CacheMode::WriteBack)
+}
+
+pub fn page_map_prot(vaddr: usize, paddr: usize, prot: Protection, cache: CacheMode) -> Option<usize> {
+    KERNEL_SPACE.map_prot(vaddr, paddr, prot, cache)
+}
+
+pub fn page_protect(vaddr: usize, prot: Protection, cache: CacheMode) -> bool {
+    KERNEL_SPACE.protect(vaddr, prot, cache)
+}
+
+pub fn map_huge(vaddr: usize, paddr: usize, size: PageSize) -> Option<usize> {
+    KERNEL_SPACE.map_huge(vaddr, paddr, size, protection_flags(READ | WRITE, CacheMode::WriteBack))
 }
 
 pub fn page_unmap(vaddr: usize) {
-    match get_entry(vaddr, false) {
-        Some(entry) => {
-            entry.set_flags(EntryFlags::empty());
-        },
-        None => {
-            return;
+    KERNEL_SPACE.unmap(vaddr);
+}
+
+pub fn get_entry<'a>(vaddr: usize) -> Option<(&'a mut Entry, PageSize)> {
+    KERNEL_SPACE.entry(vaddr)
+}
+
+pub fn translate(vaddr: usize) -> Option<usize> {
+    KERNEL_SPACE.translate(vaddr)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    pub fn bytes(&self) -> usize {
+        match *self {
+            PageSize::Size4K => 0x1000,
+            PageSize::Size2M => 0x200000,
+            PageSize::Size1G => 0x40000000,
+        }
+    }
+
+    ///
+    /// table level holding entries of this size, 0 is a page table
+    ///
+    pub fn level(&self) -> u8 {
+        match *self {
+            PageSize::Size4K => 0,
+            PageSize::Size2M => 1,
+            PageSize::Size1G => 2,
+        }
+    }
+
+    fn of_level(level: u8) -> PageSize {
+        match level {
+            0 => PageSize::Size4K,
+            1 => PageSize::Size2M,
+            _ => PageSize::Size1G,
         }
     }
 }
 
-pub fn get_entry<'a>(vaddr: usize, create: bool) -> Option<&'a mut Entry> {
-    let mut table: &mut [Entry; 512] = unsafe {get_table(cr3() as usize)};
-    for level in (0..4).rev() {
+///
+/// whether the cpu can map 1 GiB pages
+///
+pub fn has_1g_pages() -> bool {
+    let edx: u32;
+    unsafe {
+        asm!("cpuid" : "={edx}"(edx) : "{eax}"(0x80000001u32) : "ebx", "ecx" : "volatile");
+    }
+    edx & (1 << 26) != 0
+}
+
+///
+/// Finds the entry for `vaddr` at `level` under the top level table at
+/// `root`, four or five levels up. With `create` missing tables are allocated, huge pages on the
+/// way are split, and the entry is returned even if it is not present;
+/// `user` makes the tables on the way accessible from ring 3.
+/// Without `create` it gives None unless the entry is present.
+///
+pub fn walk<'a>(root: usize, vaddr: usize, level: u8, create: bool, user: bool) -> Option<&'a mut Entry> {
+    let mut table: &mut [Entry; 512] = unsafe { get_table(root) };
+    let mut cur = top_level();
+    while cur > level {
         {
-            let target = &mut table[get_index(vaddr, level)];
-            //kprint!("{:x}\n", target.toInt());
-            if target.flags().contains(PRESENT) == false {
+            let target = &mut table[get_index(vaddr, cur)];
+            if !target.is_present() {
                 if !create {
-                    return Option::None;
-                } else {
-                    if level == 0 {
-                        let flags = target.flags();
-                        target.set_flags(flags | PRESENT);
-                        return unsafe { Option::Some(::core::intrinsics::transmute(target)) };
-                    }
-                    target.try_set_paddr(create_table());
+                    return None;
+                }
+                let new_table = create_table();
+                if !target.try_set_paddr(new_table) {
+                    // lost the race, use theirs
+                    FRAME.dealloc(new_table);
                 }
             }
-            let flags = target.flags();
-            target.set_flags(flags | PRESENT | WRITABLE);
+            if target.flags().contains(HUGE_PAGE) {
+                if !create {
+                    return None;
+                }
+                split(target, cur, vaddr);
+            }
+            let mut flags = target.flags() | PRESENT | WRITABLE;
+            if user {
+                flags = flags | USER_ACCESSIBLE;
+            }
+            target.set_flags(flags);
         }
-        table = unsafe {
-            //kprint!("level = {}, addr = {:x}\n",level, table[get_index(vaddr, level)].paddr());
-            get_table(table[get_index(vaddr, level)].paddr())
+        table = unsafe { get_table(table[get_index(vaddr, cur)].paddr()) };
+        cur -= 1;
+    }
+    let entry = &mut table[get_index(vaddr, level)];
+    if !create && !entry.is_present() {
+        return None;
+    }
+    Some(entry)
+}
+
+///
+/// the present entry mapping `vaddr`, whatever its size
+///
+pub fn lookup<'a>(root: usize, vaddr: usize) -> Option<(&'a mut Entry, PageSize)> {
+    let mut table: &mut [Entry; 512] = unsafe { get_table(root) };
+    let mut cur = top_level();
+    loop {
+        let entry: &'a mut Entry = unsafe { &mut *(&mut table[get_index(vaddr, cur)] as *mut Entry) };
+        if !entry.is_present() {
+            return None;
+        }
+        if cur == 0 || (cur < 3 && entry.flags().contains(HUGE_PAGE)) {
+            return Some((entry, PageSize::of_level(cur)));
+        }
+        table = unsafe { get_table(entry.paddr()) };
+        cur -= 1;
+    }
+}
+
+///
+/// the 4 KiB entry for `vaddr`, present or not. None if there is no
+/// page table for it or a huge page covers it.
+///
+pub fn leaf<'a>(root: usize, vaddr: usize) -> Option<&'a mut Entry> {
+    let mut table: &mut [Entry; 512] = unsafe { get_table(root) };
+    let mut cur = top_level();
+    while cur > 0 {
+        let next = {
+            let entry = &table[get_index(vaddr, cur)];
+            if !entry.is_present() || (cur < 3 && entry.flags().contains(HUGE_PAGE)) {
+                return None;
+            }
+            entry.paddr()
         };
+        table = unsafe { get_table(next) };
+        cur -= 1;
     }
-    Option::None
+    Some(unsafe { &mut *(&mut table[get_index(vaddr, 0)] as *mut Entry) })
 }
 
-pub fn translate(vaddr: usize) -> Option<usize> {
-    match get_entry(vaddr, false) {
-        None => None,
-        Some(e) => Some(e.paddr())
+///
+/// replaces the huge page in `entry` (at `level`) by a table of
+/// entries one level down mapping the same memory
+///
+fn split(entry: &mut Entry, level: u8, vaddr: usize) {
+    let table_paddr = create_table();
+    let table = unsafe { get_table(table_paddr) };
+    let child_size = PageSize::of_level(level - 1).bytes();
+    let base = entry.paddr() & !(PageSize::of_level(level).bytes() - 1);
+    let mut flags = entry.flags();
+    if level == 1 {
+        // 4 KiB entries have no size bit
+        flags = flags - HUGE_PAGE;
+    }
+    for i in 0..512 {
+        table[i].set_paddr(base + i * child_size);
+        table[i].set_flags(flags);
     }
+    let mut table_flags = PRESENT | WRITABLE;
+    if entry.flags().contains(USER_ACCESSIBLE) {
+        table_flags = table_flags | USER_ACCESSIBLE;
+    }
+    let mut new = Entry(0);
+    new.set_paddr(table_paddr);
+    new.set_flags(table_flags);
+    *entry = new;
+    // one invalidation drops the whole huge translation
+    unsafe { tlb::flush(vaddr) };
 }
 
-fn get_index(vaddr: usize, level: u8) -> usize {
+pub fn get_index(vaddr: usize, level: u8) -> usize {
     let begin = 12 + level * 9;
     let end = 12 + level * 9 + 8;
     let mut temp = vaddr as usize;
@@ -131,22 +424,181 @@ fn get_index(vaddr: usize, level: u8) -> usize {
     temp
 }
 
-unsafe fn get_table<'a>(vaddr: usize) -> &'a mut [Entry; 512] {
-    let intptr = (vaddr >> 12) << 12;
-    &mut *(intptr as *mut _)
+///
+/// the table at physical address `paddr`, through the direct map
+///
+pub unsafe fn get_table<'a>(paddr: usize) -> &'a mut [Entry; 512] {
+    let intptr = (paddr >> 12) << 12;
+    &mut *(phys_to_virt(intptr) as *mut _)
 }
 
-fn create_table() -> usize {
-    //kprint!("gwa!");
-    FRAME.alloc()
+///
+/// a zeroed frame for a new page table
+///
+pub fn create_table() -> usize {
+    let ret = FRAME.alloc();
+    unsafe { ::rlibc::memset(phys_to_virt(ret) as *mut u8, 0, 4096); }
+    ret
 }
 
-pub fn map_volatile(addr: usize) -> usize {
-    let entry = get_entry(addr, true).unwrap();
-    entry.set_paddr(addr);
-    entry.set_flags(PRESENT | WRITABLE | NO_CACHE | WRITE_THROUGH);
-    unsafe {
-        tlb::flush(addr);
+///
+/// where physical memory at `paddr` can be reached
+///
+pub fn phys_to_virt(paddr: usize) -> usize {
+    paddr + PHYS_MAP_BASE
+}
+
+///
+/// physical address behind a kernel pointer. The direct map and the
+/// kernel image are linear, anything else is looked up.
+///
+pub fn virt_to_phys(vaddr: usize) -> usize {
+    if vaddr >= KERNEL_BASE {
+        return vaddr - KERNEL_BASE;
+    }
+    let mut end = DIRECT_MAP_END.load(Ordering::Relaxed);
+    if end == 0 {
+        end = BOOT_DIRECT_MAP;
+    }
+    if vaddr >= PHYS_MAP_BASE && vaddr < PHYS_MAP_BASE + end {
+        return vaddr - PHYS_MAP_BASE;
+    }
+    translate(vaddr).expect("virt_to_phys of an unmapped address")
+}
+
+///
+/// page tables `map_physical_memory` may need to cover up to `end`
+///
+pub fn direct_map_tables(end: usize) -> usize {
+    let gib = (end + BOOT_DIRECT_MAP - 1) / BOOT_DIRECT_MAP;
+    // one table for every 512 GiB, and one per GiB without 1 GiB pages
+    let ret = (gib + 511) / 512;
+    if has_1g_pages() { ret } else { ret + gib }
+}
+
+///
+/// Extends the direct map from boot.asm to cover physical memory up to
+/// `end`. Runs before the frame allocator exists, so new tables are
+/// taken one after the other from `tables`, which has to be inside the
+/// boot direct map and hold `direct_map_tables(end)` frames.
+/// Returns the number of tables used.
+///
+pub fn map_physical_memory(end: usize, tables: usize) -> usize {
+    let mut pml4 = unsafe { get_table(cr3() as usize & ADDRESS_MASK) };
+    if five_level() {
+        // boot.asm set up the P4 under the last P5 slot
+        pml4 = unsafe { get_table(pml4[get_index(PHYS_MAP_BASE, 4)].paddr()) };
     }
-    addr
-}
\ No newline at end of file
+    let huge_1g = has_1g_pages();
+    let mut leaf = PRESENT | WRITABLE | HUGE_PAGE;
+    if nx_enabled() {
+        leaf = leaf | NO_EXECUTE;
+    }
+    let mut next = tables;
+    let mut paddr = BOOT_DIRECT_MAP;
+    while paddr < end {
+        let vaddr = phys_to_virt(paddr);
+        let p3 = unsafe { get_table(table_or_new(&mut pml4[get_index(vaddr, 3)], &mut next)) };
+        let p3e = &mut p3[get_index(vaddr, 2)];
+        if huge_1g {
+            p3e.set_paddr(paddr);
+            p3e.set_flags(leaf);
+        } else {
+            let p2 = unsafe { get_table(table_or_new(p3e, &mut next)) };
+            for i in 0..512 {
+                p2[i].set_paddr(paddr + i * PageSize::Size2M.bytes());
+                p2[i].set_flags(leaf);
+            }
+        }
+        paddr += PageSize::Size1G.bytes();
+    }
+    DIRECT_MAP_END.store(paddr, Ordering::Relaxed);
+    (next - tables) / 4096
+}
+
+fn table_or_new(entry: &mut Entry, next: &mut usize) -> usize {
+    if !entry.is_present() {
+        unsafe { ::rlibc::memset(phys_to_virt(*next) as *mut u8, 0, 4096); }
+        entry.set_paddr(*next);
+        entry.set_flags(PRESENT | WRITABLE);
+        *next += 4096;
+    }
+    entry.paddr()
+}
+
+///
+/// Device memory mapped into kernel space by `map_mmio`,
+/// unmapped again when it is dropped.
+///
+pub struct MmioRegion {
+    base: usize,
+    paddr: usize,
+    len: usize,
+}
+
+impl MmioRegion {
+    ///
+    /// virtual address of the first byte
+    ///
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn paddr(&self) -> usize {
+        self.paddr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    ///
+    /// the register at `offset` bytes into the region,
+    /// it must not be used after the region is gone
+    ///
+    pub fn reg<T>(&self, offset: usize) -> MMIO<T> {
+        assert!(offset + size_of::<T>() <= self.len, "register 0x{:x} outside of the region", offset);
+        MMIO::new((self.base + offset) as *mut T)
+    }
+
+    fn pages(&self) -> usize {
+        ((self.paddr & 0xfff) + self.len + 4095) / 4096
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        FRAME.unmap_physical(self.base & !0xfff, self.pages());
+    }
+}
+
+///
+/// maps `len` bytes of device memory at `paddr` with `cache`,
+/// neither has to be page aligned. RAM is refused: the direct map has
+/// it as write-back already and two memory types for the same frame
+/// are undefined. Device holes are uncached through the MTRRs whatever
+/// the direct map says.
+///
+pub fn map_mmio(paddr: usize, len: usize, cache: CacheMode) -> MmioRegion {
+    assert!(len > 0);
+    let first = paddr & !0xfff;
+    let mut page = first;
+    while page < paddr + len {
+        if let Some(r) = FRAME.region_of(page) {
+            match r.kind {
+                RegionKind::Usable | RegionKind::AcpiReclaimable | RegionKind::AcpiNvs =>
+                    panic!("map_mmio of RAM at 0x{:x}", page),
+                _ => {},
+            }
+        }
+        page += 4096;
+    }
+    let mut ret = MmioRegion {
+        base: 0,
+        paddr: paddr,
+        len: len,
+    };
+    let vaddr = FRAME.map_physical(first, ret.pages(), cache);
+    ret.base = vaddr + (paddr & 0xfff);
+    ret
+}
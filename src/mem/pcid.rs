@@ -0,0 +1,89 @@
+use spin::Mutex;
+use super::paging::{read_cr4, write_cr4};
+
+// PCIDs are 12 bits wide, 0 is the kernel's
+const PCID_CNT: usize = 4096;
+const CR4_PGE: usize = 1 << 7;
+const CR4_PCIDE: usize = 1 << 17;
+
+/// set in cr3 to keep the TLB entries tagged with the PCID being loaded
+pub const CR3_NOFLUSH: u64 = 1 << 63;
+
+lazy_static! {
+    static ref SUPPORTED: bool = cpuid(1, 0).2 & (1 << 17) != 0;
+    static ref HAS_INVPCID: bool = cpuid(0, 0).0 >= 7 && cpuid(7, 0).1 & (1 << 10) != 0;
+}
+
+// bit n is set while PCID n belongs to an address space
+static USED: Mutex<[u64; PCID_CNT / 64]> = Mutex::new([0; PCID_CNT / 64]);
+
+///
+/// eax, ebx, ecx and edx of cpuid `leaf`, `sub`
+///
+fn cpuid(leaf: u32, sub: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid" : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+                     : "{eax}"(leaf), "{ecx}"(sub) :: "volatile");
+    }
+    (eax, ebx, ecx, edx)
+}
+
+pub fn enabled() -> bool {
+    *SUPPORTED
+}
+
+///
+/// turns PCIDs on for this cpu, has to run on every cpu
+/// while it is still on the kernel tables
+///
+pub fn enable() {
+    if enabled() {
+        unsafe { write_cr4(read_cr4() | CR4_PCIDE) };
+    }
+}
+
+///
+/// an unused PCID, 0 if they ran out. Spaces with 0 are flushed
+/// every time they are loaded.
+///
+pub fn alloc() -> usize {
+    if !enabled() {
+        return 0;
+    }
+    let mut used = USED.lock();
+    for pcid in 1..PCID_CNT {
+        if used[pcid / 64] & (1 << (pcid % 64)) == 0 {
+            used[pcid / 64] |= 1 << (pcid % 64);
+            return pcid;
+        }
+    }
+    0
+}
+
+pub fn free(pcid: usize) {
+    if pcid == 0 {
+        return;
+    }
+    let mut used = USED.lock();
+    assert!(used[pcid / 64] & (1 << (pcid % 64)) != 0, "PCID {} is not in use", pcid);
+    used[pcid / 64] &= !(1 << (pcid % 64));
+}
+
+///
+/// drops the translations of every PCID on this cpu, global ones too
+///
+pub fn flush_all_contexts() {
+    unsafe {
+        if *HAS_INVPCID {
+            // type 2, all contexts including globals
+            let desc: [u64; 2] = [0, 0];
+            asm!("invpcid $0, [$1]" :: "r"(2u64), "r"(&desc) : "memory" : "intel", "volatile");
+        } else {
+            // any change of CR4.PGE does it
+            let cr4 = read_cr4();
+            write_cr4(cr4 ^ CR4_PGE);
+            write_cr4(cr4);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use core::sync::atomic::*;
+use spin::Mutex;
+use x86::shared::tlb;
+use devices::apic;
+use super::pcid;
+use super::address_space::AddressSpace;
+
+pub const MAX_RANGES: usize = 16;
+// anything bigger flushes the whole tlb
+const FULL_FLUSH_PAGES: usize = 64;
+
+#[derive(Copy, Clone)]
+struct Range {
+    start: usize,
+    pages: usize,
+}
+
+struct Request {
+    ranges: [Range; MAX_RANGES],
+    cnt: usize,
+    full: bool,
+    // some range is in the kernel half
+    kernel: bool,
+}
+
+// one shootdown at a time, REQUEST belongs to whoever holds it
+static LOCK: Mutex<()> = Mutex::new(());
+static mut REQUEST: Request = Request {
+    ranges: [Range { start: 0, pages: 0 }; MAX_RANGES],
+    cnt: 0,
+    full: false,
+    kernel: false,
+};
+// cpus that still have to flush REQUEST
+static PENDING: AtomicUsize = ATOMIC_USIZE_INIT;
+
+///
+/// Collects ranges whose translations changed; `finish` flushes them on
+/// this cpu and on every other online cpu in `cpus` and waits until
+/// they are done. Frames that were unmapped may only be reused after that.
+///
+pub struct Batch {
+    cpus: u64,
+    req: Request,
+}
+
+impl Batch {
+    pub fn new(cpus: u64) -> Batch {
+        Batch {
+            cpus: cpus,
+            req: Request {
+                ranges: [Range { start: 0, pages: 0 }; MAX_RANGES],
+                cnt: 0,
+                full: false,
+                kernel: false,
+            },
+        }
+    }
+
+    pub fn add(&mut self, start: usize, pages: usize) {
+        if AddressSpace::is_kernel_address(start) {
+            self.req.kernel = true;
+        }
+        if self.req.full {
+            return;
+        }
+        if pages > FULL_FLUSH_PAGES || self.req.cnt == MAX_RANGES {
+            self.req.full = true;
+            return;
+        }
+        self.req.ranges[self.req.cnt] = Range { start: start & !0xfff, pages: pages };
+        self.req.cnt += 1;
+    }
+
+    pub fn finish(self) {
+        flush_local(&self.req);
+        // before the local apic is up nobody else is running
+        if !apic::local_apic_enabled() {
+            return;
+        }
+        let me = 1 << apic::get_cpu_id();
+        let targets = self.cpus as usize & apic::online_cpus() & !me;
+        if targets == 0 {
+            return;
+        }
+
+        // keep answering others while waiting, they may be waiting on us
+        let mut guard = LOCK.try_lock();
+        while guard.is_none() {
+            service();
+            unsafe { asm!("pause" :::: "volatile") };
+            guard = LOCK.try_lock();
+        }
+
+        unsafe {
+            REQUEST.ranges = self.req.ranges;
+            REQUEST.cnt = self.req.cnt;
+            REQUEST.full = self.req.full;
+            REQUEST.kernel = self.req.kernel;
+        }
+        PENDING.store(targets, Ordering::SeqCst);
+        for cpu in 0..apic::MAX_CPU {
+            if targets & (1 << cpu) != 0 {
+                apic::send_ipi(cpu as u32, apic::TLB_SHOOTDOWN_VEC);
+            }
+        }
+        while PENDING.load(Ordering::SeqCst) != 0 {
+            unsafe { asm!("pause" :::: "volatile") };
+        }
+        drop(guard);
+    }
+}
+
+///
+/// flushes `pages` pages at `start` everywhere `cpus` says they may be cached
+///
+pub fn shootdown(cpus: u64, start: usize, pages: usize) {
+    let mut batch = Batch::new(cpus);
+    batch.add(start, pages);
+    batch.finish();
+}
+
+///
+/// called from the TLB_SHOOTDOWN_VEC handler
+///
+pub fn handle_ipi() {
+    service();
+    apic::eoi();
+}
+
+///
+/// does the pending request if it is meant for this cpu. Call it while
+/// spinning on a lock whose holder may be shooting down.
+///
+pub fn service() {
+    let me = 1 << apic::get_cpu_id();
+    if PENDING.load(Ordering::SeqCst) & me == 0 {
+        return;
+    }
+    unsafe { flush_local(&REQUEST) };
+    PENDING.fetch_and(!me, Ordering::SeqCst);
+}
+
+fn flush_local(req: &Request) {
+    unsafe {
+        // kernel entries may be cached under any PCID,
+        // invlpg and a cr3 reload only reach the current one
+        if req.kernel && pcid::enabled() {
+            pcid::flush_all_contexts();
+            return;
+        }
+        if req.full {
+            tlb::flush_all();
+            return;
+        }
+        for r in &req.ranges[..req.cnt] {
+            for i in 0..r.pages {
+                tlb::flush(r.start + i * 4096);
+            }
+        }
+    }
+}
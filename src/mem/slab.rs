@@ -0,0 +1,307 @@
+use interrupt::guard::IrqMutex;
+use core::ptr;
+use core::slice;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use devices::apic;
+use super::FRAME;
+use super::paging::{phys_to_virt, virt_to_phys};
+use super::oom::OutOfMemory;
+
+const SLAB_MAGIC: usize = 0x51ab51ab;
+
+/// objects are powers of two from MIN_CLASS to MAX_CLASS bytes.
+/// The header takes the first object of every page, so bigger
+/// classes would waste too much and go to the arenas instead.
+pub const MIN_CLASS: usize = 16;
+pub const MAX_CLASS: usize = 512;
+const CLASS_CNT: usize = 6;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum SlabState {
+    // current slab of some cpu
+    Cpu,
+    // on the partial list of its class
+    Partial,
+    // ran out of objects and was dropped by its cpu, on no list
+    Full,
+}
+
+///
+/// Header at the start of every slab page. The objects follow,
+/// each aligned to its own size.
+///
+struct Slab {
+    magic: usize,
+    class: usize,
+    lock: IrqMutex<()>,
+    free: *mut FreeObject,
+    in_use: usize,
+    capacity: usize,
+    state: SlabState,
+    next: *mut Slab,
+    prev: *mut Slab,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+///
+/// Size class allocator for small objects.
+/// Every cpu allocates from its own current slab per class; slabs that
+/// ran full get back on the shared partial list when an object is freed.
+///
+/// Lock order: cpu cache -> partial list -> slab
+///
+pub struct SlabAllocator {
+    partial: &'static [IrqMutex<*mut Slab>],
+    caches: &'static [IrqMutex<*mut Slab>],
+    pages: AtomicUsize,
+    objects: AtomicUsize,
+}
+
+unsafe impl Sync for SlabAllocator {}
+unsafe impl Send for SlabAllocator {}
+
+///
+/// smallest class holding `len` bytes aligned to `align`,
+/// None if it has to go to the arenas
+///
+pub fn class_for(len: usize, align: usize) -> Option<usize> {
+    let mut size = MIN_CLASS;
+    let mut class = 0;
+    while size < len || size < align {
+        size <<= 1;
+        class += 1;
+    }
+    if size > MAX_CLASS {
+        None
+    } else {
+        Some(class)
+    }
+}
+
+pub fn class_size(class: usize) -> usize {
+    MIN_CLASS << class
+}
+
+impl SlabAllocator {
+    pub fn new() -> SlabAllocator {
+        // one partial list per class followed by the per-cpu current slabs
+        let cnt = CLASS_CNT * (apic::MAX_CPU + 1);
+        let bytes = cnt * size_of::<IrqMutex<*mut Slab>>();
+        let addr = FRAME.alloc_multiple((bytes - 1) / 4096 + 1);
+        unsafe {
+            // a zeroed lock is unlocked and holds a null pointer
+            ::rlibc::memset(addr as *mut u8, 0, bytes);
+            let all: &'static [IrqMutex<*mut Slab>] = slice::from_raw_parts(addr as *const _, cnt);
+            SlabAllocator {
+                partial: &all[..CLASS_CNT],
+                caches: &all[CLASS_CNT..],
+                pages: AtomicUsize::new(0),
+                objects: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    pub fn allocate(&self, class: usize) -> Result<usize, OutOfMemory> {
+        loop {
+            {
+                let mut current = self.cache(class).lock();
+                loop {
+                    let slab_ptr: *mut Slab = *current;
+                    if let Some(slab) = unsafe { slab_ptr.as_mut() } {
+                        let _g = slab.lock.lock();
+                        if let Some(obj) = unsafe { slab.free.as_mut() } {
+                            slab.free = obj.next;
+                            slab.in_use += 1;
+                            self.objects.fetch_add(1, Ordering::Relaxed);
+                            return Ok(obj as *mut FreeObject as usize);
+                        }
+                        // whoever frees the next object puts it on the partial list
+                        slab.state = SlabState::Full;
+                    }
+                    *current = self.refill(class);
+                    if (*current).is_null() {
+                        break;
+                    }
+                }
+            }
+            // the page is allocated with no lock held, so this is
+            // only an atomic allocation if the caller's is
+            let slab = Slab::create(class)?;
+            self.pages.fetch_add(1, Ordering::Relaxed);
+            let mut partial = self.partial[class].lock();
+            push_partial(&mut *partial, slab);
+        }
+    }
+
+    ///
+    /// frees the current slabs of every cpu that hold no objects,
+    /// caches that are busy are skipped. Returns the number of pages freed.
+    ///
+    pub fn shrink(&self) -> usize {
+        let mut ret = 0;
+        for cache in self.caches.iter() {
+            if let Some(mut current) = cache.try_lock() {
+                let slab_ptr: *mut Slab = *current;
+                if let Some(slab) = unsafe { slab_ptr.as_mut() } {
+                    let g = slab.lock.lock();
+                    if slab.in_use != 0 {
+                        continue;
+                    }
+                    slab.magic = 0;
+                    drop(g);
+                    *current = ptr::null_mut();
+                    FRAME.dealloc(virt_to_phys(slab_ptr as usize));
+                    self.pages.fetch_sub(1, Ordering::Relaxed);
+                    ret += 1;
+                }
+            }
+        }
+        ret
+    }
+
+    pub fn deallocate(&self, ptr: usize) {
+        let slab_ptr = (ptr & !0xfff) as *mut Slab;
+        let slab: &mut Slab = unsafe { &mut *slab_ptr };
+        assert!(slab.magic == SLAB_MAGIC, "0x{:x} is not a slab object", ptr);
+        {
+            let _g = slab.lock.lock();
+            let obj = ptr as *mut FreeObject;
+            unsafe { (*obj).next = slab.free; }
+            slab.free = obj;
+            slab.in_use -= 1;
+            self.objects.fetch_sub(1, Ordering::Relaxed);
+            let settled = match slab.state {
+                SlabState::Cpu => true,
+                SlabState::Partial => slab.in_use != 0,
+                SlabState::Full => false,
+            };
+            if settled {
+                return;
+            }
+        }
+        self.settle(slab_ptr);
+    }
+
+    ///
+    /// moves a slab whose last free changed where it belongs
+    ///
+    fn settle(&self, slab_ptr: *mut Slab) {
+        let slab: &mut Slab = unsafe { &mut *slab_ptr };
+        let mut partial = self.partial[slab.class].lock();
+        let g = slab.lock.lock();
+        match slab.state {
+            SlabState::Full if slab.in_use == 0 => {
+                slab.magic = 0;
+                drop(g);
+                FRAME.dealloc(virt_to_phys(slab_ptr as usize));
+                self.pages.fetch_sub(1, Ordering::Relaxed);
+            },
+            SlabState::Full => {
+                drop(g);
+                push_partial(&mut *partial, slab_ptr);
+            },
+            SlabState::Partial if slab.in_use == 0 => {
+                if let Some(prev) = unsafe { slab.prev.as_mut() } {
+                    prev.next = slab.next;
+                } else {
+                    *partial = slab.next;
+                }
+                if let Some(next) = unsafe { slab.next.as_mut() } {
+                    next.prev = slab.prev;
+                }
+                slab.magic = 0;
+                drop(g);
+                FRAME.dealloc(virt_to_phys(slab_ptr as usize));
+                self.pages.fetch_sub(1, Ordering::Relaxed);
+            },
+            // somebody else got here first
+            _ => {}
+        }
+    }
+
+    ///
+    /// takes a slab off the partial list, null if it is empty
+    ///
+    fn refill(&self, class: usize) -> *mut Slab {
+        let mut partial = self.partial[class].lock();
+        let head: *mut Slab = *partial;
+        if let Some(slab) = unsafe { head.as_mut() } {
+            let _g = slab.lock.lock();
+            *partial = slab.next;
+            if let Some(next) = unsafe { slab.next.as_mut() } {
+                next.prev = ptr::null_mut();
+            }
+            slab.next = ptr::null_mut();
+            slab.prev = ptr::null_mut();
+            slab.state = SlabState::Cpu;
+        }
+        head
+    }
+
+    ///
+    /// pages held by slabs and objects handed out from them
+    ///
+    pub fn usage(&self) -> (usize, usize) {
+        (self.pages.load(Ordering::Relaxed), self.objects.load(Ordering::Relaxed))
+    }
+
+    fn cache(&self, class: usize) -> &IrqMutex<*mut Slab> {
+        // only a hint for spreading the locks, any slot is correct
+        let cpu = if apic::local_apic_enabled() {
+            apic::get_cpu_id() as usize % apic::MAX_CPU
+        } else {
+            0
+        };
+        &self.caches[cpu * CLASS_CNT + class]
+    }
+}
+
+///
+/// puts a slab that no cpu uses at the head of a partial list,
+/// the list has to be locked
+///
+fn push_partial(head: &mut *mut Slab, slab_ptr: *mut Slab) {
+    let slab: &mut Slab = unsafe { &mut *slab_ptr };
+    let _g = slab.lock.lock();
+    slab.state = SlabState::Partial;
+    slab.prev = ptr::null_mut();
+    slab.next = *head;
+    if let Some(next) = unsafe { (*head).as_mut() } {
+        next.prev = slab_ptr;
+    }
+    *head = slab_ptr;
+}
+
+impl Slab {
+    fn create(class: usize) -> Result<*mut Slab, OutOfMemory> {
+        let addr = phys_to_virt(FRAME.try_alloc()?);
+        let slab = addr as *mut Slab;
+        let size = class_size(class);
+        let first = ((size_of::<Slab>() - 1) / size + 1) * size;
+        let capacity = (4096 - first) / size;
+        unsafe {
+            ptr::write(slab, Slab {
+                magic: SLAB_MAGIC,
+                class: class,
+                lock: IrqMutex::new(()),
+                free: ptr::null_mut(),
+                in_use: 0,
+                capacity: capacity,
+                state: SlabState::Cpu,
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+            });
+            // lowest address ends up at the head
+            for i in (0..capacity).rev() {
+                let obj = (addr + first + i * size) as *mut FreeObject;
+                (*obj).next = (*slab).free;
+                (*slab).free = obj;
+            }
+        }
+        Ok(slab)
+    }
+}
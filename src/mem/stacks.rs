@@ -0,0 +1,119 @@
+use interrupt::guard::IrqMutex;
+use collections::vec::Vec;
+use core::fmt;
+use core::str;
+
+// thread names are cut to this many bytes
+const NAME_LEN: usize = 24;
+// frames printed by backtrace
+const MAX_FRAMES: usize = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StackKind {
+    Thread,
+    /// the interrupt stack table entry it is in
+    Ist(usize),
+    /// the one an AP starts on
+    Ap,
+}
+
+///
+/// A stack handed out by `alloc_stack`, from its guard page up to its top
+///
+#[derive(Copy, Clone)]
+pub struct Stack {
+    pub guard: usize,
+    pub top: usize,
+    pub kind: StackKind,
+    name: [u8; NAME_LEN],
+    name_len: usize,
+}
+
+impl Stack {
+    pub fn name(&self) -> &str {
+        str::from_utf8(&self.name[..self.name_len]).unwrap_or("?")
+    }
+
+    ///
+    /// whether `addr` is on the stack itself, the guard page is not
+    ///
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.guard + 4096 && addr <= self.top
+    }
+}
+
+impl fmt::Display for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            StackKind::Thread => write!(f, "thread {}", self.name()),
+            StackKind::Ist(i) => write!(f, "IST stack {}", i),
+            StackKind::Ap => write!(f, "AP boot stack"),
+        }
+    }
+}
+
+lazy_static! {
+    static ref STACKS: IrqMutex<Vec<Stack>> = IrqMutex::new(Vec::new());
+}
+
+///
+/// records the stack `alloc_stack(pages)` returned `top` for
+///
+pub fn register(top: usize, pages: usize, kind: StackKind, name: &str) {
+    let mut stack = Stack {
+        guard: top - (pages + 1) * 4096,
+        top: top,
+        kind: kind,
+        name: [0; NAME_LEN],
+        name_len: 0,
+    };
+    // don't cut a character in half
+    let mut len = if name.len() < NAME_LEN { name.len() } else { NAME_LEN };
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    stack.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    stack.name_len = len;
+    STACKS.lock().push(stack);
+}
+
+///
+/// call before the stack is freed
+///
+pub fn unregister(top: usize) {
+    let mut stacks = STACKS.lock();
+    if let Some(i) = stacks.iter().position(|s| s.top == top) {
+        stacks.swap_remove(i);
+    }
+}
+
+///
+/// The stack whose guard page holds `addr`. Used by the fault handlers,
+/// so it gives up instead of spinning if the registry is locked.
+///
+pub fn guard_hit(addr: usize) -> Option<Stack> {
+    let stacks = match STACKS.try_lock() {
+        Some(s) => s,
+        None => return None,
+    };
+    stacks.iter().find(|s| addr >= s.guard && addr < s.guard + 4096).map(|s| *s)
+}
+
+///
+/// follows the frame pointers from `rbp` as long as they stay on `stack`
+///
+pub fn backtrace(rip: usize, rbp: usize, stack: &Stack) {
+    kprint!("backtrace:\n  0x{:x}\n", rip);
+    let mut rbp = rbp;
+    for _ in 0..MAX_FRAMES {
+        if rbp % 8 != 0 || !stack.contains(rbp) || !stack.contains(rbp + 16) {
+            break;
+        }
+        let ret = unsafe { *((rbp + 8) as *const usize) };
+        if ret == 0 {
+            break;
+        }
+        kprint!("  0x{:x}\n", ret);
+        rbp = unsafe { *(rbp as *const usize) };
+    }
+}
@@ -0,0 +1,157 @@
+use spin::Mutex;
+use core::sync::atomic::*;
+use fs::block::BLOCK_DEVICES;
+use super::FRAME;
+use super::bitmap::Bitmap;
+use super::frame::order_for;
+use super::paging::phys_to_virt;
+use super::address_space::AddressSpace;
+use super::oom;
+
+const SECTOR_SIZE: usize = 512;
+const SECTORS_PER_SLOT: usize = 4096 / SECTOR_SIZE;
+
+///
+/// A run of sectors on a block device cut into page sized slots.
+/// A set bit in `used` is a slot holding a page.
+///
+struct SwapArea {
+    device: usize,
+    first_sector: usize,
+    slots: usize,
+    used: Bitmap<'static>,
+}
+
+static AREA: Mutex<Option<SwapArea>> = Mutex::new(None);
+static USED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+///
+/// swaps to `slots` pages worth of sectors starting at `first_sector`
+/// of `BLOCK_DEVICES[device]`, whatever is there gets overwritten
+///
+pub fn enable(device: usize, first_sector: usize, slots: usize) {
+    assert!(device < BLOCK_DEVICES.len(), "no block device {}", device);
+    assert!(slots > 0);
+    let mut area = AREA.lock();
+    assert!(area.is_none(), "swap is already enabled");
+
+    // whole bytes, the bits past the end stay set
+    let bits = ((slots + 7) / 8) * 8;
+    let order = order_for(bits / 8);
+    let mut used = Bitmap::new(phys_to_virt(FRAME.alloc_order(order)), bits);
+    used.fill(false);
+    for slot in slots..bits {
+        used.set(slot, true);
+    }
+    *area = Some(SwapArea {
+        device: device,
+        first_sector: first_sector,
+        slots: slots,
+        used: used,
+    });
+    oom::register("swap", reclaim_current);
+    kprint!("swap: {} KiB on block device {}\n", slots * 4, device);
+}
+
+///
+/// parses the `swap=` command line value `device,first_sector,slots`,
+/// numbers are decimal or 0x prefixed hex
+///
+pub fn parse_config(arg: &str) -> Option<(usize, usize, usize)> {
+    let mut ret = [0; 3];
+    let mut parts = arg.split(',');
+    for i in 0..3 {
+        let part = match parts.next() {
+            Some(p) => p,
+            None => return None,
+        };
+        let parsed = if part.starts_with("0x") {
+            usize::from_str_radix(&part[2..], 16)
+        } else {
+            part.parse::<usize>()
+        };
+        ret[i] = match parsed {
+            Ok(n) => n,
+            Err(_) => return None,
+        };
+    }
+    if parts.next().is_some() || ret[2] == 0 {
+        return None;
+    }
+    Some((ret[0], ret[1], ret[2]))
+}
+
+pub fn enabled() -> bool {
+    AREA.lock().is_some()
+}
+
+///
+/// number of slots holding a page
+///
+pub fn used() -> usize {
+    USED.load(Ordering::Relaxed)
+}
+
+///
+/// a free slot, None if swap is off or full
+///
+pub fn alloc_slot() -> Option<usize> {
+    let area = AREA.lock();
+    let ret = match *area {
+        Some(ref a) => a.used.set_first_unused(),
+        None => None,
+    };
+    if ret.is_some() {
+        USED.fetch_add(1, Ordering::Relaxed);
+    }
+    ret
+}
+
+pub fn free_slot(slot: usize) {
+    let mut area = AREA.lock();
+    let a = area.as_mut().expect("swap is not enabled");
+    assert!(slot < a.slots && a.used.get(slot), "freeing unused swap slot {}", slot);
+    a.used.set(slot, false);
+    USED.fetch_sub(1, Ordering::Relaxed);
+}
+
+///
+/// copies the frame at `paddr` to `slot`
+///
+pub fn write_page(slot: usize, paddr: usize) {
+    let (device, sector) = locate(slot);
+    let buf = phys_to_virt(paddr);
+    for i in 0..SECTORS_PER_SLOT {
+        BLOCK_DEVICES[device].write_block_raw((buf + i * SECTOR_SIZE) as *mut u8, sector + i);
+    }
+}
+
+///
+/// copies `slot` to the frame at `paddr`
+///
+pub fn read_page(slot: usize, paddr: usize) {
+    let (device, sector) = locate(slot);
+    let buf = phys_to_virt(paddr);
+    for i in 0..SECTORS_PER_SLOT {
+        BLOCK_DEVICES[device].read_block_raw((buf + i * SECTOR_SIZE) as *mut u8, sector + i);
+    }
+}
+
+///
+/// device and first sector of `slot`, the I/O itself runs without the lock
+///
+fn locate(slot: usize) -> (usize, usize) {
+    let area = AREA.lock();
+    let a = area.as_ref().expect("swap is not enabled");
+    assert!(slot < a.slots);
+    (a.device, a.first_sector + slot * SECTORS_PER_SLOT)
+}
+
+///
+/// Shrink hook. Only the space running on this cpu is known to stay
+/// alive while its pages are written out, so the others are never
+/// paged out under pressure; there is no list of all spaces to walk.
+///
+fn reclaim_current(frames: usize) -> usize {
+    AddressSpace::current().reclaim(frames)
+}
@@ -0,0 +1,187 @@
+use interrupt::guard::IrqMutex;
+use core::sync::atomic::*;
+use devices::apic;
+
+const MAX_TRACKED: usize = 1024;
+
+/// return addresses kept per allocation
+pub const CALLER_DEPTH: usize = 4;
+
+#[derive(Copy, Clone)]
+struct Record {
+    addr: usize,
+    len: usize,
+    tag: &'static str,
+    callers: [usize; CALLER_DEPTH],
+}
+
+struct Tracker {
+    records: [Record; MAX_TRACKED],
+    cnt: usize,
+    // allocations that did not fit in the table
+    dropped: usize,
+    tags: [&'static str; apic::MAX_CPU],
+}
+
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+static TRACKER: IrqMutex<Tracker> = IrqMutex::new(Tracker {
+    records: [Record { addr: 0, len: 0, tag: "", callers: [0; CALLER_DEPTH] }; MAX_TRACKED],
+    cnt: 0,
+    dropped: 0,
+    tags: [""; apic::MAX_CPU],
+});
+
+///
+/// Leak tracking. While enabled every live heap allocation is recorded
+/// with the tag of its cpu and the return addresses that led to it,
+/// `dump` lists whatever is still outstanding.
+/// The table is fixed size, so none of this touches the heap.
+///
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+///
+/// stops recording, the table is kept for `dump`
+///
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+///
+/// Tags allocations made on this cpu until the guard is dropped.
+/// The tag is per cpu, so do not hold it across a reschedule.
+///
+pub struct TagGuard {
+    prev: &'static str,
+}
+
+pub fn tagged(tag: &'static str) -> TagGuard {
+    let mut t = TRACKER.lock();
+    let cpu = cpu_slot();
+    let prev = t.tags[cpu];
+    t.tags[cpu] = tag;
+    TagGuard { prev: prev }
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        let mut t = TRACKER.lock();
+        let cpu = cpu_slot();
+        t.tags[cpu] = self.prev;
+    }
+}
+
+///
+/// return addresses of the function this is inlined into,
+/// innermost first. Relies on frame pointers.
+///
+#[inline(always)]
+pub fn callers() -> [usize; CALLER_DEPTH] {
+    let mut ret = [0; CALLER_DEPTH];
+    let mut rbp: usize;
+    unsafe { asm!("mov %rbp, $0" : "=r"(rbp)); }
+    for i in 0..CALLER_DEPTH {
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+        let next = unsafe { *(rbp as *const usize) };
+        ret[i] = unsafe { *((rbp + 8) as *const usize) };
+        // frames further out live higher up the stack
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    ret
+}
+
+pub fn record(addr: usize, len: usize, callers: [usize; CALLER_DEPTH]) {
+    let mut t = TRACKER.lock();
+    if t.cnt == MAX_TRACKED {
+        t.dropped += 1;
+        return;
+    }
+    let tag = t.tags[cpu_slot()];
+    let idx = t.cnt;
+    t.records[idx] = Record { addr: addr, len: len, tag: tag, callers: callers };
+    t.cnt += 1;
+}
+
+///
+/// allocations made before tracking was enabled are silently ignored
+///
+pub fn forget(addr: usize) {
+    let mut t = TRACKER.lock();
+    if let Some(idx) = t.find(addr) {
+        let last = t.cnt - 1;
+        t.records[idx] = t.records[last];
+        t.cnt -= 1;
+    }
+}
+
+pub fn moved(old: usize, new: usize, len: usize) {
+    let mut t = TRACKER.lock();
+    if let Some(idx) = t.find(old) {
+        t.records[idx].addr = new;
+        t.records[idx].len = len;
+    }
+}
+
+///
+/// number of outstanding allocations and their total size for `tag`
+///
+pub fn outstanding(tag: &str) -> (usize, usize) {
+    let t = TRACKER.lock();
+    let mut cnt = 0;
+    let mut bytes = 0;
+    for r in &t.records[..t.cnt] {
+        if r.tag == tag {
+            cnt += 1;
+            bytes += r.len;
+        }
+    }
+    (cnt, bytes)
+}
+
+///
+/// prints every outstanding allocation over serial
+///
+pub fn dump() {
+    let t = TRACKER.lock();
+    sprint!("heap: {} outstanding allocations, {} not tracked\n", t.cnt, t.dropped);
+    for r in &t.records[..t.cnt] {
+        sprint!("  0x{:x} {} bytes [{}] from", r.addr, r.len, r.tag);
+        for c in r.callers.iter() {
+            if *c == 0 {
+                break;
+            }
+            sprint!(" 0x{:x}", c);
+        }
+        sprint!("\n");
+    }
+}
+
+impl Tracker {
+    fn find(&self, addr: usize) -> Option<usize> {
+        for i in 0..self.cnt {
+            if self.records[i].addr == addr {
+                return Some(i);
+            }
+        }
+        None
+    }
+}
+
+fn cpu_slot() -> usize {
+    if apic::local_apic_enabled() {
+        apic::get_cpu_id() as usize % apic::MAX_CPU
+    } else {
+        0
+    }
+}
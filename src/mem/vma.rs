@@ -0,0 +1,176 @@
+use super::paging::*;
+use super::address_space::{AddressSpace, KERNEL_SPACE};
+use super::FRAME;
+use super::frame::UNMAP_BATCH;
+use super::swap;
+
+pub use super::paging::{Protection, READ, WRITE, EXEC, USER};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VmaKind {
+    /// frames are handed out as they are, contents undefined
+    Anonymous,
+    /// frames are cleared before they are mapped
+    ZeroFill,
+    /// never mapped, every access is a fault
+    Guard,
+    /// device memory starting at the given physical address, uncached
+    Mmio(usize),
+}
+
+///
+/// A page aligned range `[start, end)` of an address space that is
+/// populated on the first access.
+///
+#[derive(Debug, Copy, Clone)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub kind: VmaKind,
+    pub prot: Protection,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum FaultError {
+    /// the address is not covered by any VMA
+    NoVma,
+    /// the access hit a guard VMA
+    Guard(Vma),
+    /// the VMA does not allow this kind of access
+    Protection(Vma),
+    /// no frame to bring the page back from swap into
+    OutOfMemory,
+}
+
+// page fault error code bits
+pub const FAULT_PRESENT: u64 = 1 << 0;
+pub const FAULT_WRITE: u64 = 1 << 1;
+pub const FAULT_USER: u64 = 1 << 2;
+pub const FAULT_FETCH: u64 = 1 << 4;
+
+impl Vma {
+    pub fn new(start: usize, end: usize, kind: VmaKind, prot: Protection) -> Vma {
+        assert!(start % 4096 == 0 && end % 4096 == 0 && start < end);
+        Vma {
+            start: start,
+            end: end,
+            kind: kind,
+            prot: prot,
+        }
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    pub fn overlaps(&self, other: &Vma) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    pub fn allows(&self, code: u64) -> bool {
+        if code & FAULT_WRITE != 0 && !self.prot.contains(WRITE) {
+            return false;
+        }
+        if code & FAULT_FETCH != 0 && !self.prot.contains(EXEC) {
+            return false;
+        }
+        if code & FAULT_USER != 0 && !self.prot.contains(USER) {
+            return false;
+        }
+        true
+    }
+
+    fn entry_flags(&self) -> EntryFlags {
+        let cache = match self.kind {
+            VmaKind::Mmio(_) => CacheMode::Uncached,
+            _ => CacheMode::WriteBack,
+        };
+        protection_flags(self.prot, cache)
+    }
+
+    ///
+    /// maps the page holding `addr`, `code` is the page fault error code.
+    /// Fails if there is no frame for it, nothing is mapped then.
+    ///
+    pub fn populate(&self, space: &AddressSpace, addr: usize, code: u64) -> Result<(), FaultError> {
+        let page = addr & !0xfff;
+        if self.kind == VmaKind::ZeroFill && code & FAULT_WRITE == 0 {
+            // reads share the zero page until the first write
+            let mut flags = self.entry_flags();
+            if flags.contains(WRITABLE) {
+                flags = (flags - WRITABLE) | COPY_ON_WRITE;
+            }
+            space.map(page, FRAME.zero_page(), flags);
+            return Ok(());
+        }
+        let paddr = match self.kind {
+            VmaKind::Anonymous => FRAME.try_alloc().map_err(|_| FaultError::OutOfMemory)?,
+            VmaKind::ZeroFill => {
+                let frame = FRAME.try_alloc().map_err(|_| FaultError::OutOfMemory)?;
+                unsafe { ::rlibc::memset(phys_to_virt(frame) as *mut u8, 0, 4096); }
+                frame
+            },
+            VmaKind::Mmio(base) => base + (page - self.start),
+            VmaKind::Guard => unreachable!(),
+        };
+        space.map(page, paddr, self.entry_flags());
+        Ok(())
+    }
+
+    ///
+    /// whether its pages may be written out to swap
+    ///
+    pub fn swappable(&self) -> bool {
+        match self.kind {
+            VmaKind::Anonymous | VmaKind::ZeroFill => true,
+            _ => false,
+        }
+    }
+
+    ///
+    /// unmaps whatever got populated, memory backed kinds drop
+    /// their reference to the frames and swap slots
+    ///
+    pub fn depopulate(&self, space: &AddressSpace) {
+        // frames are only let go once no cpu can reach them any more
+        let mut frames = [0usize; UNMAP_BATCH];
+        let mut start = self.start;
+        while start < self.end {
+            let left = (self.end - start) / 4096;
+            let chunk = if left < UNMAP_BATCH { left } else { UNMAP_BATCH };
+            let mut n = 0;
+            for i in 0..chunk {
+                let page = start + i * 4096;
+                if let Some(paddr) = space.unmap_deferred(page) {
+                    frames[n] = paddr;
+                    n += 1;
+                } else if let Some(slot) = space.take_swapped(page) {
+                    swap::free_slot(slot);
+                }
+            }
+            if n > 0 {
+                space.shootdown(start, chunk);
+            }
+            if self.swappable() {
+                for f in &frames[..n] {
+                    FRAME.put_ref(*f);
+                }
+            }
+            start += chunk * 4096;
+        }
+    }
+}
+
+///
+/// Called by the page fault handler with the faulting address and the
+/// error code. Ok means the page is there now and the access can be
+/// retried.
+///
+pub fn handle_fault(addr: usize, code: u64) -> Result<(), FaultError> {
+    let space = if AddressSpace::is_kernel_address(addr) {
+        &*KERNEL_SPACE
+    } else {
+        AddressSpace::current()
+    };
+    space.fault(addr, code)
+}
@@ -0,0 +1,180 @@
+use interrupt::guard::IrqMutex;
+
+const MAX_FREE_RANGES: usize = 512;
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Copy, Clone)]
+struct Range {
+    base: usize,
+    len: usize,
+}
+
+struct RangeList {
+    top: usize,
+    cnt: usize,
+    ranges: [Range; MAX_FREE_RANGES],
+}
+
+///
+/// Hands out page aligned ranges of kernel virtual address space.
+/// Everything at or above `top` has never been used; ranges below it
+/// that were given back are kept sorted in `ranges` and coalesced.
+///
+pub struct VirtualRangeAllocator {
+    inner: IrqMutex<RangeList>,
+}
+
+impl VirtualRangeAllocator {
+    pub const fn new(base: usize) -> VirtualRangeAllocator {
+        VirtualRangeAllocator {
+            inner: IrqMutex::new(RangeList {
+                top: base,
+                cnt: 0,
+                ranges: [Range { base: 0, len: 0 }; MAX_FREE_RANGES],
+            }),
+        }
+    }
+
+    ///
+    /// first fit over the freed ranges, falls back to bumping `top`
+    ///
+    pub fn alloc(&self, len: usize) -> usize {
+        self.alloc_aligned(len, PAGE_SIZE, 0)
+    }
+
+    ///
+    /// returns `base` such that `base + offset` is a multiple of `align`
+    ///
+    pub fn alloc_aligned(&self, len: usize, align: usize, offset: usize) -> usize {
+        assert!(len % PAGE_SIZE == 0 && len > 0);
+        assert!(align.is_power_of_two() && align >= PAGE_SIZE);
+        assert!(offset % PAGE_SIZE == 0);
+        let mut list = self.inner.lock();
+        for i in 0..list.cnt {
+            let r = list.ranges[i];
+            let ret = align_with_offset(r.base, align, offset);
+            if ret + len > r.base + r.len {
+                continue;
+            }
+            // keep whatever is left on both sides
+            list.remove(i);
+            if ret > r.base {
+                list.insert(r.base, ret - r.base);
+            }
+            if ret + len < r.base + r.len {
+                list.insert(ret + len, r.base + r.len - ret - len);
+            }
+            return ret;
+        }
+        let ret = align_with_offset(list.top, align, offset);
+        if ret > list.top {
+            let top = list.top;
+            list.insert(top, ret - top);
+        }
+        list.top = ret + len;
+        ret
+    }
+
+    ///
+    /// takes exactly `[base, base + len)`, fails if any of it is in use
+    ///
+    pub fn claim(&self, base: usize, len: usize) -> bool {
+        assert!(base % PAGE_SIZE == 0 && len % PAGE_SIZE == 0);
+        let mut list = self.inner.lock();
+        // no free range ends at `top`, so nothing below it can straddle it
+        if base >= list.top {
+            if base > list.top {
+                let top = list.top;
+                list.insert(top, base - top);
+            }
+            list.top = base + len;
+            return true;
+        }
+        for i in 0..list.cnt {
+            let r = list.ranges[i];
+            if r.base <= base && base + len <= r.base + r.len {
+                list.remove(i);
+                if base > r.base {
+                    list.insert(r.base, base - r.base);
+                }
+                if base + len < r.base + r.len {
+                    list.insert(base + len, r.base + r.len - base - len);
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn free(&self, base: usize, len: usize) {
+        assert!(base % PAGE_SIZE == 0 && len % PAGE_SIZE == 0);
+        let mut list = self.inner.lock();
+        assert!(base + len <= list.top);
+        list.insert(base, len);
+
+        // give the highest range back to the bump pointer
+        if list.cnt > 0 {
+            let last = list.ranges[list.cnt - 1];
+            if last.base + last.len == list.top {
+                list.top = last.base;
+                let idx = list.cnt - 1;
+                list.remove(idx);
+            }
+        }
+    }
+}
+
+fn align_with_offset(base: usize, align: usize, offset: usize) -> usize {
+    let target = base + offset;
+    let aligned = (target + align - 1) & !(align - 1);
+    aligned - offset
+}
+
+impl RangeList {
+    fn insert(&mut self, base: usize, len: usize) {
+        // find the first range above the new one
+        let mut pos = 0;
+        while pos < self.cnt && self.ranges[pos].base < base {
+            pos += 1;
+        }
+        if pos > 0 {
+            let prev = self.ranges[pos - 1];
+            assert!(prev.base + prev.len <= base, "double free of virtual range 0x{:x}", base);
+        }
+        if pos < self.cnt {
+            assert!(base + len <= self.ranges[pos].base, "double free of virtual range 0x{:x}", base);
+        }
+
+        let merge_prev = pos > 0 && self.ranges[pos - 1].base + self.ranges[pos - 1].len == base;
+        let merge_next = pos < self.cnt && base + len == self.ranges[pos].base;
+
+        if merge_prev && merge_next {
+            self.ranges[pos - 1].len += len + self.ranges[pos].len;
+            self.remove(pos);
+        } else if merge_prev {
+            self.ranges[pos - 1].len += len;
+        } else if merge_next {
+            self.ranges[pos].base = base;
+            self.ranges[pos].len += len;
+        } else {
+            if self.cnt == MAX_FREE_RANGES {
+                kprint!("vrange: free list full, leaking 0x{:x} + 0x{:x}\n", base, len);
+                return;
+            }
+            let mut i = self.cnt;
+            while i > pos {
+                self.ranges[i] = self.ranges[i - 1];
+                i -= 1;
+            }
+            self.ranges[pos] = Range { base: base, len: len };
+            self.cnt += 1;
+        }
+    }
+
+    fn remove(&mut self, index: usize) {
+        for i in index..self.cnt - 1 {
+            self.ranges[i] = self.ranges[i + 1];
+        }
+        self.cnt -= 1;
+    }
+}
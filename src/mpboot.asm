@@ -6,7 +6,7 @@ extern check_cpuid
 extern check_long_mode
 extern enable_paging
 extern set_up_SSE
-extern gdt_pointer
+extern boot_gdt_pointer
 extern gdt_data
 extern gdt_code
 extern mp_main
@@ -14,7 +14,10 @@ extern create_stack
 
 %define get_addr(a) (a - mp_start + 0x1000)
 
-section .mp
+; acquire_lock runs from here before paging is on and v_lock is
+; written from long mode, both at their physical addresses. The
+; kernel identity maps it until every cpu is up.
+section .mp progbits alloc exec write
 bits 16
 mp_start:
     cli
@@ -61,7 +64,7 @@ mp_start_32:
     mov eax, set_up_SSE
     call eax
 
-    mov eax, gdt_pointer
+    mov eax, boot_gdt_pointer
 
     lgdt [eax]
     mov ax, gdt_data
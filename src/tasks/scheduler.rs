@@ -11,7 +11,10 @@ pub struct Scheduler {
     thread_current: CPULocal<WrappedThread>,
     ready_queue: Queue<WrappedThread>,
     idle_thread: CPULocal<WrappedThread>,
-    is_idling: CPULocal<bool>
+    is_idling: CPULocal<bool>,
+    // dead thread we switched away from last time, kept alive
+    // until we are off its stack
+    zombie: CPULocal<WrappedThread>
 }
 
 impl Scheduler {
@@ -20,11 +23,16 @@ impl Scheduler {
             thread_current: CPULocal::create(),
             ready_queue: Queue::create(),
             idle_thread: CPULocal::create(),
-            is_idling: CPULocal::create()
+            is_idling: CPULocal::create(),
+            zombie: CPULocal::create()
         }
     }
 
     pub fn schedule(&self) {
+        // whoever died last on this cpu is no longer running,
+        // so its stack can go away now
+        drop(self.zombie.into_inner());
+
         let prev: WrappedThread;
         if self.thread_current.get_mut().is_none() {
             prev = Arc::new(RefCell::new(KThread::boot_strap_thread()));
@@ -35,6 +43,7 @@ impl Scheduler {
                     self.ready_queue.enqueue(prev.clone()); // then enqueue
                 } else {
                     //kprint!("Thread is dead. Count: {}\n", Arc::strong_count(&prev));
+                    self.zombie.set(prev.clone());
                 }
             }
         }
@@ -63,6 +72,20 @@ impl Scheduler {
 
     }
 
+    ///
+    /// ends the thread running on this cpu without unwinding it,
+    /// e.g. after it overflowed its stack
+    ///
+    pub fn kill_current(&self) -> ! {
+        match self.thread_current.get_mut() {
+            Some(t) => t.borrow().kill(),
+            None => panic!("no thread to kill on this cpu"),
+        }
+        unsafe { ::x86::shared::irq::disable(); }
+        self.schedule();
+        unreachable!();
+    }
+
     pub fn insert_thread(&self, t: WrappedThread) {
         self.ready_queue.enqueue(t);
     }
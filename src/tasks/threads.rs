@@ -10,6 +10,8 @@ use core::cell::RefCell;
 type DoThreadFunc = fn(usize) -> usize;
 pub type WrappedThread = Arc<RefCell<KThread>>;
 
+const STACK_PAGES: usize = 3;
+
 
 pub struct KThread {
     pub name: String,
@@ -17,6 +19,7 @@ pub struct KThread {
     runnable: AtomicBool,
     pub running: AtomicBool,
     rsp: usize,
+    stack_top: usize,
     dead: AtomicBool,
     //rip: usize,
 }
@@ -30,12 +33,16 @@ pub fn new_thread(entry_point: DoThreadFunc, name: &str) -> WrappedThread {
 
 impl KThread {
     pub fn create(entry_point: DoThreadFunc, name: &str) -> WrappedThread {
+        let _tag = mem::track::tagged("thread");
+        let stack_top = mem::FRAME.alloc_stack(STACK_PAGES);
+        mem::stacks::register(stack_top, STACK_PAGES, mem::stacks::StackKind::Thread, name);
         let mut ret = Arc::new(RefCell::new(KThread {
             name: name.to_string(),
             entry_point: entry_point,
             runnable: ATOMIC_BOOL_INIT,
             running: ATOMIC_BOOL_INIT,
-            rsp: mem::FRAME.alloc_stack(3) - 8,
+            rsp: stack_top - 8,
+            stack_top: stack_top,
             dead: ATOMIC_BOOL_INIT
         }));
 
@@ -66,6 +73,7 @@ impl KThread {
             runnable: AtomicBool::new(true),
             running: AtomicBool::new(true),
             rsp: 0,
+            stack_top: 0,
             dead: AtomicBool::new(false)
         }
     }
@@ -119,6 +127,13 @@ impl KThread {
         unreachable!();
     }
 
+    ///
+    /// the scheduler drops it the next time it switches away
+    ///
+    pub fn kill(&self) {
+        self.dead.store(true, Ordering::SeqCst);
+    }
+
     pub fn is_dead(&self) -> bool {
         self.dead.load(Ordering::SeqCst)
     }
@@ -133,6 +148,11 @@ impl KThread {
 impl Drop for KThread {
     fn drop(&mut self) {
         kprint!("Dropping {}\n", self.name.as_str());
+        // the bootstrap thread runs on the stack from boot.asm
+        if self.stack_top != 0 {
+            mem::stacks::unregister(self.stack_top);
+            mem::FRAME.dealloc_stack(self.stack_top, STACK_PAGES);
+        }
     }
 }
 